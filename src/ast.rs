@@ -0,0 +1,247 @@
+use crate::lexer::spec::{Cond, Op, Reg};
+use std::fmt;
+use std::ops::Range;
+
+/// Node of the tree with the location of its source
+#[derive(Debug, Clone, PartialEq)]
+pub struct Spanned<T> {
+    pub node: T,
+    pub span: Range<usize>,
+}
+
+impl<T> Spanned<T> {
+    pub fn new(node: T, span: Range<usize>) -> Self {
+        Spanned { node, span }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UnaryOp {
+    Neg,
+    Not,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinOp {
+    Mul,
+    Div,
+    Rem,
+    Add,
+    Sub,
+    Shl,
+    Shr,
+    Lt,
+    LtEq,
+    Gt,
+    GtEq,
+    Eq,
+    Neq,
+    And,
+    Xor,
+    Or,
+}
+
+impl BinOp {
+    /// operators with a higher precedence are applied first
+    pub fn precedence(self) -> u8 {
+        match self {
+            BinOp::Mul | BinOp::Div | BinOp::Rem => 8,
+            BinOp::Add | BinOp::Sub => 7,
+            BinOp::Shl | BinOp::Shr => 6,
+            BinOp::Lt | BinOp::LtEq | BinOp::Gt | BinOp::GtEq => 5,
+            BinOp::Eq | BinOp::Neq => 4,
+            BinOp::And => 3,
+            BinOp::Xor => 2,
+            BinOp::Or => 1,
+        }
+    }
+}
+
+/// Constant expression evaluated at assembly time
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Value(u16),
+    Id(String),
+    /// `$`, address of the current instruction
+    Here,
+    Unary {
+        op: UnaryOp,
+        expr: Box<Spanned<Expr>>,
+    },
+    Binary {
+        op: BinOp,
+        lhs: Box<Spanned<Expr>>,
+        rhs: Box<Spanned<Expr>>,
+    },
+}
+
+impl Expr {
+    /// identifiers read by the expression
+    pub fn identifiers(&self) -> Vec<&str> {
+        match self {
+            Expr::Value(_) | Expr::Here => vec![],
+            Expr::Id(id) => vec![id.as_str()],
+            Expr::Unary { expr, .. } => expr.node.identifiers(),
+            Expr::Binary { lhs, rhs, .. } => {
+                let mut ids = lhs.node.identifiers();
+                ids.extend(rhs.node.identifiers());
+                ids
+            }
+        }
+    }
+
+    /// replace the identifiers `value_of` knows by their value
+    pub fn bind(&mut self, value_of: &impl Fn(&str) -> Option<u16>) {
+        match self {
+            Expr::Id(id) => {
+                if let Some(val) = value_of(id) {
+                    *self = Expr::Value(val);
+                }
+            }
+            Expr::Unary { expr, .. } => expr.node.bind(value_of),
+            Expr::Binary { lhs, rhs, .. } => {
+                lhs.node.bind(value_of);
+                rhs.node.bind(value_of);
+            }
+            Expr::Value(_) | Expr::Here => (),
+        }
+    }
+
+    /// whether the expression reads `$`
+    pub fn uses_here(&self) -> bool {
+        match self {
+            Expr::Value(_) | Expr::Id(_) => false,
+            Expr::Here => true,
+            Expr::Unary { expr, .. } => expr.node.uses_here(),
+            Expr::Binary { lhs, rhs, .. } => lhs.node.uses_here() || rhs.node.uses_here(),
+        }
+    }
+}
+
+impl fmt::Display for UnaryOp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UnaryOp::Neg => write!(f, "-"),
+            UnaryOp::Not => write!(f, "~"),
+        }
+    }
+}
+
+impl fmt::Display for BinOp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let symbol = match self {
+            BinOp::Mul => "*",
+            BinOp::Div => "/",
+            BinOp::Rem => "%",
+            BinOp::Add => "+",
+            BinOp::Sub => "-",
+            BinOp::Shl => "<<",
+            BinOp::Shr => ">>",
+            BinOp::Lt => "<",
+            BinOp::LtEq => "<=",
+            BinOp::Gt => ">",
+            BinOp::GtEq => ">=",
+            BinOp::Eq => "==",
+            BinOp::Neq => "!=",
+            BinOp::And => "&",
+            BinOp::Xor => "^",
+            BinOp::Or => "|",
+        };
+        write!(f, "{symbol}")
+    }
+}
+
+/// nested binary expressions are written between parentheses
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let nested = |expr: &Expr| match expr {
+            Expr::Binary { .. } => format!("({expr})"),
+            _ => expr.to_string(),
+        };
+        match self {
+            Expr::Value(val) => write!(f, "{val}"),
+            Expr::Id(id) => write!(f, "{id}"),
+            Expr::Here => write!(f, "$"),
+            Expr::Unary { op, expr } => write!(f, "{op}{}", nested(&expr.node)),
+            Expr::Binary { op, lhs, rhs } => {
+                write!(f, "{} {op} {}", nested(&lhs.node), nested(&rhs.node))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Statement {
+    /// `A = 0x7fff`, `A = end - start`
+    Load {
+        dest: Spanned<Reg>,
+        value: Spanned<Expr>,
+        /// value when it is known while parsing, one which doesn't fit
+        /// a data mode word is loaded by two instructions
+        constant: Option<u16>,
+    },
+    /// `D = A`
+    Transfer { dest: Spanned<Reg>, src: Spanned<Reg> },
+    /// `D = ~A`
+    Not { dest: Spanned<Reg>, src: Spanned<Reg> },
+    /// `D = D & *A`
+    Binary {
+        dest: Spanned<Reg>,
+        a: Spanned<Reg>,
+        op: Spanned<Op>,
+        b: Spanned<Reg>,
+    },
+    /// `D >=`
+    Condition { reg: Spanned<Reg>, cond: Spanned<Cond> },
+    /// `JMP`
+    Jump,
+    /// `main:`
+    Label(Spanned<String>),
+    /// `DEFINE mask 1 << 8`
+    Define {
+        id: Spanned<String>,
+        value: Spanned<Expr>,
+    },
+    /// `.arch v1`
+    Arch(String),
+    /// `.word 1, table + 2`, one data word per value,
+    /// `.fill` and `.zero` repeat their value
+    Word(Vec<Spanned<Expr>>),
+    /// `ORG 0x100`, the address of the next word
+    Org(u16),
+    /// `.section name`, `.text` or `.data`
+    Section(String),
+    /// `.entry start`, label the program starts from
+    Entry(Spanned<String>),
+}
+
+/// Statements produced by a macro call
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expansion {
+    pub name: String,
+    /// `name arg1, arg2`
+    pub call: Range<usize>,
+    /// indexes of the statements
+    pub statements: Range<usize>,
+}
+
+/// Statements of a source file in order, macro calls are expanded
+#[derive(Debug, Default, PartialEq)]
+pub struct Program {
+    pub statements: Vec<Spanned<Statement>>,
+    /// an expansion nested in another one comes first
+    pub expansions: Vec<Expansion>,
+    /// identifiers read by conditions or in lines which aren't
+    /// assembled, they aren't reported as unused
+    pub referenced: Vec<String>,
+}
+
+impl Program {
+    /// macro calls which produced a statement, innermost first
+    pub fn calls(&self, index: usize) -> Vec<&Expansion> {
+        self.expansions
+            .iter()
+            .filter(|expansion| expansion.statements.contains(&index))
+            .collect()
+    }
+}
@@ -0,0 +1,274 @@
+use crate::lexer::parse_number;
+use crate::parser::ColType;
+use crate::sim::Machine;
+use crate::source::SourceMap;
+use miette::{miette, LabeledSpan, Severity};
+use std::collections::HashMap;
+use std::ops::Range;
+
+const HELP: &str = "\
+s, step [n]            execute the next (n) instructions
+n, next                execute the instructions of the current line
+c, continue            run until a breakpoint, a watchpoint or the end of the program
+b, break <label|line>  stop before the instruction of a label or a source line
+w, watch <name|adr>    stop after a write to a memory address
+p, print               print the registers and the memory
+l, list                show the next instruction
+q, quit                leave the debugger";
+
+/// Step debugger driving the simulator from text commands
+pub struct Debugger<'a> {
+    pub machine: Machine<'a>,
+    /// source of each instruction
+    spans: Vec<Range<usize>>,
+    symbols: HashMap<String, ColType>,
+    source: SourceMap,
+    /// index of the instructions to stop at
+    breakpoints: Vec<usize>,
+    watchpoints: Vec<u16>,
+    max_cycles: usize,
+}
+
+impl<'a> Debugger<'a> {
+    pub fn new(
+        machine: Machine<'a>,
+        spans: Vec<Range<usize>>,
+        symbols: HashMap<String, ColType>,
+        source: SourceMap,
+        max_cycles: usize,
+    ) -> Self {
+        Debugger {
+            machine,
+            spans,
+            symbols,
+            source,
+            breakpoints: vec![],
+            watchpoints: vec![],
+            max_cycles,
+        }
+    }
+
+    /// execute a command and returns what should be printed,
+    /// None when the user wants to quit
+    pub fn execute(&mut self, command: &str) -> Option<String> {
+        let mut words = command.split_whitespace();
+        let name = words.next().unwrap_or_default();
+        let arg = words.next();
+
+        let output = match (name, arg) {
+            ("s" | "step", count) => match count.map(|c| c.parse::<usize>()) {
+                None => self.run(|_, steps| steps == 1),
+                Some(Ok(0)) => "The number of steps must be at least 1".to_string(),
+                Some(Ok(count)) => self.run(|_, steps| steps == count),
+                Some(Err(_)) => format!("Invalid number of steps {}", count.unwrap()),
+            },
+            ("n" | "next", None) => {
+                let line = self.line_of_pc();
+                self.run(|debugger, _| debugger.line_of_pc() != line)
+            }
+            ("c" | "continue", None) => self.run(|_, _| false),
+            ("b" | "break", Some(target)) => self.add_breakpoint(target),
+            ("w" | "watch", Some(target)) => self.add_watchpoint(target),
+            ("p" | "print", None) => self.machine.to_string(),
+            ("l" | "list", None) => self.location(),
+            ("h" | "help", None) => HELP.to_string(),
+            ("q" | "quit", None) => return None,
+            _ => format!("Unknown command {command}, type help to list the commands"),
+        };
+
+        Some(output)
+    }
+
+    /// execute instructions until `done` returns true for the debugger and
+    /// the number of executed instructions or something stops the program
+    fn run(&mut self, done: impl Fn(&Self, usize) -> bool) -> String {
+        let mut steps = 0;
+
+        loop {
+            if self.machine.cycles >= self.max_cycles {
+                return format!("Maximum number of cycles reached\n{}", self.location());
+            }
+            if self.machine.step().is_none() {
+                return match self.machine.is_over() {
+                    true => "The program is over".to_string(),
+                    false => format!("The simulator doesn't know this instruction\n{}", self.location()),
+                };
+            }
+            steps += 1;
+
+            if let Some(adr) = self.machine.last_write {
+                if self.watchpoints.contains(&adr) {
+                    let val = self.machine.memory[&adr];
+                    return format!("Watchpoint 0x{adr:04x} = 0x{val:04x}\n{}", self.location());
+                }
+            }
+            if self.breakpoints.contains(&self.machine.pc) {
+                return format!("Breakpoint\n{}", self.location());
+            }
+            if done(self, steps) {
+                return self.location();
+            }
+        }
+    }
+
+    /// line in the assembled file, 0 in an included one
+    fn line_of(&self, offset: usize) -> usize {
+        match self.source.line_of(offset) {
+            (name, line) if name == self.source.main() => line,
+            _ => 0,
+        }
+    }
+
+    fn line_of_pc(&self) -> Option<(String, usize)> {
+        self.spans.get(self.machine.pc).map(|span| {
+            let (name, line) = self.source.line_of(span.start);
+            (name.to_string(), line)
+        })
+    }
+
+    fn add_breakpoint(&mut self, target: &str) -> String {
+        let index = match target.parse::<usize>() {
+            // first instruction starting at this line or after it,
+            // the words filling the gaps have no line
+            Ok(line) => self
+                .spans
+                .iter()
+                .position(|span| !span.is_empty() && self.line_of(span.start) >= line),
+            Err(_) => match self.symbols.get(target) {
+                Some(symbol) => Some(self.machine.index_of(symbol.val)),
+                None => return format!("Unknown label {target}"),
+            },
+        };
+
+        match index.filter(|index| *index < self.spans.len()) {
+            Some(index) => {
+                self.breakpoints.push(index);
+                let (name, line) = self.source.line_of(self.spans[index].start);
+                match name == self.source.main() {
+                    true => format!("Breakpoint set on line {line}"),
+                    false => format!("Breakpoint set on line {line} of {name}"),
+                }
+            }
+            None => format!("No instruction at {target}"),
+        }
+    }
+
+    fn add_watchpoint(&mut self, target: &str) -> String {
+        let adr = match self.symbols.get(target) {
+            Some(symbol) => symbol.val,
+            None => match parse_number(target) {
+                Ok(adr) => adr,
+                Err(e) => return format!("Unknown address {e}"),
+            },
+        };
+        self.watchpoints.push(adr);
+        format!("Watching 0x{adr:04x}")
+    }
+
+    /// registers and next instruction highlighted in the source
+    fn location(&self) -> String {
+        let mut registers = format!(
+            "A = 0x{:04x}  V = 0x{:04x}  D = 0x{:04x}",
+            self.machine.a, self.machine.v, self.machine.d
+        );
+        for (name, val) in &self.machine.registers {
+            registers += &format!("  {name} = 0x{val:04x}");
+        }
+
+        match self.spans.get(self.machine.pc) {
+            Some(span) => {
+                let report = miette!(
+                    severity = Severity::Advice,
+                    labels = vec![LabeledSpan::at(span.clone(), "next")],
+                    "{registers}"
+                );
+                format!("{:?}", report.with_source_code(self.source.clone()))
+            }
+            None => format!("{registers}\nThe program is over"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::spec::{arch_v1::ArchV1, Targets};
+    use crate::parser::parse;
+    use crate::sections::SectionLayout;
+    use crate::source::Loader;
+
+    fn start(src: &str) -> Debugger<'static> {
+        let mut loader = Loader::default();
+        let tokens = loader.load("test.asm", src.to_string());
+        let parser_report = parse(&tokens, &mut loader, &mut Targets::default(), &[], &SectionLayout::default(), false);
+        let machine = Machine::new(parser_report.instructions, &ArchV1);
+        Debugger::new(
+            machine,
+            parser_report.spans,
+            parser_report.id_collect,
+            loader.sources,
+            100,
+        )
+    }
+
+    #[test]
+    fn test_step() {
+        let mut debugger = start("A = 1\nD = A\nA = 2\nD = D + A");
+
+        debugger.execute("step");
+        assert_eq!(1, debugger.machine.a);
+        debugger.execute("s 2");
+        assert_eq!(3, debugger.machine.cycles);
+        assert_eq!(2, debugger.machine.a);
+        // nothing is executed
+        assert!(debugger.execute("s 0").unwrap().contains("at least 1"));
+        assert_eq!(3, debugger.machine.cycles);
+        assert!(debugger.execute("s").unwrap().contains("over"));
+        assert_eq!(3, debugger.machine.d);
+        assert!(debugger.execute("s two").unwrap().contains("Invalid"));
+    }
+
+    #[test]
+    fn test_breakpoints() {
+        let src = "A = 1\nloop:\nD = A\nA = loop\nJMP";
+        let mut debugger = start(src);
+
+        assert!(debugger.execute("b foo").unwrap().contains("Unknown"));
+        assert!(debugger.execute("b 42").unwrap().contains("No instruction"));
+        debugger.execute("b loop");
+        debugger.execute("c");
+        assert_eq!(1, debugger.machine.pc);
+        debugger.execute("c");
+        assert_eq!(1, debugger.machine.pc);
+        assert_eq!(4, debugger.machine.cycles);
+
+        let mut debugger = start(src);
+        assert!(debugger.execute("break 4").unwrap().contains("line 4"));
+        debugger.execute("continue");
+        assert_eq!(2, debugger.machine.pc);
+        debugger.execute("next");
+        assert_eq!(3, debugger.machine.pc);
+    }
+
+    #[test]
+    fn test_watchpoints() {
+        let src = "DEFINE io_adr 0x7fff\nA = 5\nD = A\nA = io_adr\n*A = D\nD = A\nA = 0x10\n*A = D";
+        let mut debugger = start(src);
+
+        assert!(debugger.execute("w io_adr").unwrap().contains("0x7fff"));
+        debugger.execute("watch 16");
+        assert!(debugger.execute("c").unwrap().contains("0x7fff = 0x0005"));
+        assert!(debugger.execute("c").unwrap().contains("0x0010 = 0x7fff"));
+        assert!(debugger.execute("w nowhere").unwrap().contains("Unknown"));
+    }
+
+    #[test]
+    fn test_commands() {
+        let mut debugger = start("A = 1");
+
+        assert!(debugger.execute("p").unwrap().contains("A = 0x0000"));
+        assert!(debugger.execute("help").unwrap().contains("continue"));
+        assert!(debugger.execute("jump").unwrap().contains("Unknown command"));
+        assert_eq!(None, debugger.execute("q"));
+    }
+}
@@ -0,0 +1,196 @@
+use crate::lexer::spec::{Architecture, Instruction, Op, Reg};
+use miette::{miette, Error, LabeledSpan};
+use std::ops::Range;
+
+/// read the words of a bit stream produced by the assembler,
+/// separators and colors are ignored
+pub fn read_bit_stream(text: &str) -> Result<Vec<(u16, Range<usize>)>, Error> {
+    let mut words = vec![];
+    let mut word = 0;
+    let mut len = 0;
+    let mut start = 0;
+    let mut in_escape = false;
+
+    for (i, c) in text.char_indices() {
+        match c {
+            // colors are ANSI escape sequences like \x1b[1;32m
+            '\x1b' => in_escape = true,
+            'm' if in_escape => in_escape = false,
+            _ if in_escape => (),
+            '0' | '1' => {
+                if len == 0 {
+                    start = i;
+                }
+                word = (word << 1) | (c == '1') as u16;
+                len += 1;
+                if len == 16 {
+                    words.push((word, start..i + 1));
+                    word = 0;
+                    len = 0;
+                }
+            }
+            _ => (),
+        }
+    }
+
+    if len != 0 {
+        return Err(miette!(
+            labels = vec![LabeledSpan::at(start..text.len(), format!("only {len} bits"))],
+            "Error: Incomplete word at the end of the bit stream"
+        ));
+    }
+
+    Ok(words)
+}
+
+/// read the big endian words of a binary produced by the assembler
+pub fn read_binary(bytes: &[u8]) -> Result<Vec<(u16, Range<usize>)>, Error> {
+    if !bytes.len().is_multiple_of(2) {
+        return Err(miette!(
+            "Error: The binary holds {} bytes which isn't a whole number of words",
+            bytes.len()
+        ));
+    }
+
+    Ok(bytes
+        .chunks(2)
+        .enumerate()
+        .map(|(i, pair)| (u16::from_be_bytes([pair[0], pair[1]]), 2 * i..2 * i + 2))
+        .collect())
+}
+
+/// assembly source of an instruction
+pub fn source(inst: &Instruction, arch: &dyn Architecture) -> String {
+    match *inst {
+        Instruction::Load(value) => format!("{} = 0x{:x}", arch.load_register(), value),
+        Instruction::Alu {
+            op: Op::Or,
+            a,
+            b: Reg::Zero,
+            dest,
+        } => format!("{dest} = {a}"),
+        Instruction::Alu {
+            op: Op::Not,
+            a,
+            b: Reg::A,
+            dest,
+        } => format!("{dest} = ~{a}"),
+        // `~` is written with a single operand, the word is kept as is
+        Instruction::Alu { op: Op::Not, .. } => format!(".word 0x{:x}", inst.encode(arch)),
+        Instruction::Alu { op, a, b, dest } => format!("{dest} = {a} {op} {b}"),
+        Instruction::Condition { cond, reg } => format!("{reg} {cond}"),
+        Instruction::Jump => "JMP".to_string(),
+        Instruction::Data(word) => format!(".word 0x{word:x}"),
+    }
+}
+
+/// turn words back into assembly source, words that can't be decoded
+/// are reported and left as comments
+pub fn disassemble(words: &[(u16, Range<usize>)], arch: &dyn Architecture) -> (String, Vec<Error>) {
+    let mut lines = vec![];
+    let mut errors = vec![];
+
+    for (adr, (word, span)) in words.iter().enumerate() {
+        match arch.decode(*word) {
+            Ok(inst) => lines.push(source(&inst, arch)),
+            Err(e) => {
+                lines.push(format!("; invalid word {word:016b}: {e}"));
+                errors.push(miette!(
+                    labels = vec![LabeledSpan::at(span.clone(), format!("word {adr}"))],
+                    "Error: Unable to decode {word:016b}, {e}"
+                ));
+            }
+        }
+    }
+
+    (lines.join("\n"), errors)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::spec::{arch_v1::ArchV1, Targets};
+    use crate::lexer::{LexError, Token};
+    use crate::parser::parse;
+    use crate::sections::SectionLayout;
+    use crate::source::Loader;
+    use logos::Logos;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_read_bit_stream() {
+        let words = read_bit_stream("1000000000101010 \x1b[1;32m0\x1b[0m111000000000000\n").unwrap();
+        assert_eq!(vec![(0x802a, 0..16), (0x7000, 24..44)], words);
+
+        let words = read_bit_stream("10000000001010100111000000000000").unwrap();
+        assert_eq!(vec![(0x802a, 0..16), (0x7000, 16..32)], words);
+
+        assert!(read_bit_stream("1000000000101010 0111").is_err());
+    }
+
+    #[test]
+    fn test_read_binary() {
+        let words = read_binary(&[0x80, 0x2a, 0x70, 0x00]).unwrap();
+        assert_eq!(vec![(0x802a, 0..2), (0x7000, 2..4)], words);
+
+        assert!(read_binary(&[0x80, 0x2a, 0x70]).is_err());
+    }
+
+    #[test]
+    fn test_disassemble() {
+        let src = "A = 0x7fff\nD = D & *A\nD = A\nA = ~D\nD !=\nJMP";
+        let words: Vec<(u16, Range<usize>)> = [
+            0xffff_u16,
+            0b0010000100001100,
+            0b0011000000110100,
+            0b0101000100000000,
+            0b0101000100110110,
+            0b0111000000000000,
+        ]
+        .into_iter()
+        .map(|word| (word, 0..0))
+        .collect();
+
+        let (disassembled, errors) = disassemble(&words, &ArchV1);
+        assert!(errors.is_empty());
+        assert_eq!(src, disassembled);
+
+        // the source must assemble back into the same words
+        let lex = Token::lexer(&disassembled);
+        let tokens: Vec<(Result<Token, LexError>, Range<usize>)> = lex.spanned().collect();
+        let encoded: Vec<u16> = parse(&tokens, &mut Loader::default(), &mut Targets::default(), &[], &SectionLayout::default(), false)
+            .instructions
+            .iter()
+            .map(|inst| inst.encode(&ArchV1))
+            .collect();
+        assert_eq!(words.iter().map(|(word, _)| *word).collect::<Vec<u16>>(), encoded);
+    }
+
+    #[test]
+    fn test_disassemble_not() {
+        // `~` reading B from another register than A has no source of its own
+        let words = vec![(0b0101000100110000, 0..16)];
+
+        let (disassembled, errors) = disassemble(&words, &ArchV1);
+        assert!(errors.is_empty());
+        assert_eq!(".word 0x5130", disassembled);
+
+        let lex = Token::lexer(&disassembled);
+        let tokens: Vec<(Result<Token, LexError>, Range<usize>)> = lex.spanned().collect();
+        let encoded: Vec<u16> = parse(&tokens, &mut Loader::default(), &mut Targets::default(), &[], &SectionLayout::default(), false)
+            .instructions
+            .iter()
+            .map(|inst| inst.encode(&ArchV1))
+            .collect();
+        assert_eq!(vec![0b0101000100110000], encoded);
+    }
+
+    #[test]
+    fn test_disassemble_invalid_words() {
+        let words = vec![(0b0010001100001100, 0..16), (0b0010000101001100, 17..33)];
+
+        let (disassembled, errors) = disassemble(&words, &ArchV1);
+        assert_eq!(2, errors.len());
+        assert_eq!(2, disassembled.lines().filter(|l| l.starts_with(';')).count());
+    }
+}
@@ -0,0 +1,137 @@
+use crate::ast::{BinOp, Expr, Spanned, UnaryOp};
+use crate::parser::ColType;
+use miette::{miette, Error, LabeledSpan};
+use std::collections::HashMap;
+use std::ops::Range;
+
+/// Why a constant expression can't be evaluated
+pub enum EvalError {
+    /// identifiers which are not declared, with the span of each use
+    Unknown(Vec<(String, Range<usize>)>),
+    Invalid(Error),
+}
+
+/// value of a constant expression where `$` is `here`,
+/// the identifiers it reads are marked as visited
+pub fn eval(
+    expr: &Spanned<Expr>,
+    here: usize,
+    symbols: &mut HashMap<String, ColType>,
+) -> Result<i64, EvalError> {
+    match &expr.node {
+        Expr::Value(val) => Ok(*val as i64),
+        Expr::Here => Ok(here as i64),
+        Expr::Id(id) => match symbols.get_mut(id) {
+            Some(symbol) => {
+                symbol.visited = true;
+                Ok(symbol.val as i64)
+            }
+            None => Err(EvalError::Unknown(vec![(id.clone(), expr.span.clone())])),
+        },
+        Expr::Unary { op, expr } => {
+            let val = eval(expr, here, symbols)?;
+            Ok(match op {
+                UnaryOp::Neg => -val,
+                UnaryOp::Not => !val,
+            })
+        }
+        Expr::Binary { op, lhs, rhs } => {
+            // both sides are evaluated so that every unknown identifier is reported
+            let (a, b) = match (eval(lhs, here, symbols), eval(rhs, here, symbols)) {
+                (Ok(a), Ok(b)) => (a, b),
+                (Err(EvalError::Unknown(mut uses)), Err(EvalError::Unknown(others))) => {
+                    uses.extend(others);
+                    return Err(EvalError::Unknown(uses));
+                }
+                (Err(e), _) | (_, Err(e)) => return Err(e),
+            };
+            apply(*op, a, b, &rhs.span)?
+                .ok_or_else(|| {
+                    EvalError::Invalid(miette!(
+                        labels = vec![LabeledSpan::at(expr.span.clone(), "Here")],
+                        "Error: Overflow in constant expression"
+                    ))
+                })
+        }
+    }
+}
+
+/// None when the result overflows
+fn apply(op: BinOp, a: i64, b: i64, rhs_span: &Range<usize>) -> Result<Option<i64>, EvalError> {
+    let invalid = |message: &str, label: String| {
+        EvalError::Invalid(miette!(
+            labels = vec![LabeledSpan::at(rhs_span.clone(), label)],
+            "Error: {message}"
+        ))
+    };
+
+    Ok(match op {
+        BinOp::Add => a.checked_add(b),
+        BinOp::Sub => a.checked_sub(b),
+        BinOp::Mul => a.checked_mul(b),
+        BinOp::Div | BinOp::Rem if b == 0 => {
+            return Err(invalid("Division by zero", "this is 0".to_string()))
+        }
+        BinOp::Div => a.checked_div(b),
+        BinOp::Rem => a.checked_rem(b),
+        BinOp::Shl | BinOp::Shr if !(0..16).contains(&b) => {
+            return Err(invalid(
+                "Shift out of range",
+                format!("{b} isn't between 0 and 15"),
+            ))
+        }
+        BinOp::Shl => a.checked_mul(1 << b),
+        BinOp::Shr => Some(a >> b),
+        // comparisons are 1 when true and 0 otherwise
+        BinOp::Lt => Some((a < b) as i64),
+        BinOp::LtEq => Some((a <= b) as i64),
+        BinOp::Gt => Some((a > b) as i64),
+        BinOp::GtEq => Some((a >= b) as i64),
+        BinOp::Eq => Some((a == b) as i64),
+        BinOp::Neq => Some((a != b) as i64),
+        BinOp::And => Some(a & b),
+        BinOp::Xor => Some(a ^ b),
+        BinOp::Or => Some(a | b),
+    })
+}
+
+/// report of a value which doesn't fit between 0 and `max`
+pub fn out_of_range(span: Range<usize>, value: i64, max: u16) -> Error {
+    miette!(
+        labels = vec![LabeledSpan::at(span, format!("evaluates to {value}"))],
+        help = format!("The value must be between 0 and {max}"),
+        "Error: Value out of range"
+    )
+}
+
+/// 16 bits word holding a value, negative ones in two's complement
+pub fn word(value: i64) -> Option<u16> {
+    (-0x8000..=0xffff).contains(&value).then_some(value as u16)
+}
+
+/// report of a value which doesn't fit in a word
+pub fn out_of_word(span: Range<usize>, value: i64) -> Error {
+    miette!(
+        labels = vec![LabeledSpan::at(span, format!("evaluates to {value}"))],
+        help = "The value must be between -32768 and 65535",
+        "Error: Value out of range"
+    )
+}
+
+/// one report per unknown identifier, labelling all its uses
+pub fn unknown_reports(uses: Vec<(String, Range<usize>)>) -> Vec<Error> {
+    let mut unknown: Vec<(String, Vec<LabeledSpan>)> = vec![];
+
+    for (id, span) in uses {
+        let label = LabeledSpan::at(span, "unknown id");
+        match unknown.iter_mut().find(|(other, _)| *other == id) {
+            Some((_, labels)) => labels.push(label),
+            None => unknown.push((id, vec![label])),
+        }
+    }
+
+    unknown
+        .into_iter()
+        .map(|(id, labels)| miette!(labels = labels, "Error: Unrecognized identifier {id}"))
+        .collect()
+}
@@ -1,37 +1,88 @@
 use logos::{Lexer, Logos};
+use miette::{miette, Error, LabeledSpan};
+use std::ops::Range;
 
 /* >> Architecture being used << */
 #[path = "spec.rs"]
 pub mod spec;
-use spec::arch_v1::*;
+use spec::*;
 
-/// This trait is either used by the lexer to produce Token with the new method
-/// or by the parser to generate the bit stream from a Token
+/// This trait is used by the lexer to produce Token with the new method
 pub trait HandleToken {
-    // get the bit stream from an item (Reg, Op, Inst)
-    fn bit_stream(&self) -> String {
-        String::new()
-    }
-
-    fn new(lex: &mut Lexer<Token>) -> Option<Self>
+    fn new(lex: &mut Lexer<Token>) -> Result<Self, LexError>
     where
-        Self: Sized; // todo: default implementation qui renvoit une erreur en spécifiant le type Self
+        Self: Sized;
 }
 
-macro_rules! parse_number {
-    ($name:ident, $prefix:expr, $radix:expr) => {
-        fn $name(lex: &mut Lexer<Token>) -> Option<u16> {
-            let raw_slice = lex.slice().trim_start_matches($prefix);
-            match u16::from_str_radix(raw_slice, $radix) {
-                Ok(n) if n <= MAX_LOAD_VALUE => Some(n),
-                Ok(_) | Err(_) => None,
-            }
+/// Why a part of the source can't be turned into a Token
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum LexError {
+    /// the value doesn't fit in a word
+    ValueOutOfRange { max: u16 },
+    InvalidDigit { radix: u32 },
+    /// the register doesn't exist on the targeted architecture
+    UnknownRegister { valid: Vec<String> },
+    /// the operation or condition doesn't exist on the targeted architecture
+    Unsupported { kind: &'static str, valid: Vec<String> },
+    UnknownArch { valid: Vec<String> },
+    /// the escape sequence after `\` isn't known
+    UnknownEscape,
+    /// the character code doesn't fit in a byte
+    CharacterOutOfRange,
+    #[default]
+    UnexpectedCharacter,
+}
+
+impl LexError {
+    /// labelled report of the error found at `span`
+    pub fn report(&self, span: Range<usize>) -> Error {
+        match self {
+            LexError::ValueOutOfRange { max } => miette!(
+                labels = vec![LabeledSpan::at(span, format!("bigger than {max}"))],
+                help = "Values are 16 bits words",
+                "Error: Value out of range"
+            ),
+            LexError::InvalidDigit { radix } => miette!(
+                labels = vec![LabeledSpan::at(span, "Here")],
+                help = "Numbers are decimal, hexadecimal with 0x, octal with 0o or binary with 0b",
+                "Error: Invalid digit in a base {radix} number"
+            ),
+            LexError::UnknownRegister { valid } => miette!(
+                labels = vec![LabeledSpan::at(span, "unknown register")],
+                help = format!("Valid registers are {}", valid.join(", ")),
+                "Error: Unknown register"
+            ),
+            LexError::Unsupported { kind, valid } => miette!(
+                labels = vec![LabeledSpan::at(span, format!("unsupported {kind}"))],
+                help = format!("The targeted architecture supports {}", valid.join(" ")),
+                "Error: Unsupported {kind}"
+            ),
+            LexError::UnknownArch { valid } => miette!(
+                labels = vec![LabeledSpan::at(span, "unknown architecture")],
+                help = format!("Known architectures are {}", valid.join(", ")),
+                "Error: Unknown architecture"
+            ),
+            LexError::UnknownEscape => miette!(
+                labels = vec![LabeledSpan::at(span, "Here")],
+                help = r#"Valid escapes are \n \r \t \0 \\ \' \" and \x followed by two hexadecimal digits"#,
+                "Error: Unknown escape sequence"
+            ),
+            LexError::CharacterOutOfRange => miette!(
+                labels = vec![LabeledSpan::at(span, "bigger than 255")],
+                help = "Characters are bytes",
+                "Error: Character out of range"
+            ),
+            LexError::UnexpectedCharacter => miette!(
+                labels = vec![LabeledSpan::at(span, "Here")],
+                "Error: Unexpected character"
+            ),
         }
-    };
+    }
 }
 
-#[derive(Logos, Debug, PartialEq)]
-#[logos(skip r"[ \t\r\n\f]+")]
+#[derive(Logos, Debug, Clone, PartialEq)]
+#[logos(skip r"[ \t\r\f]+")]
+#[logos(error = LexError)]
 pub enum Token {
     // Condition has a higher priority than Operation
     // watchout you need to escape the good char
@@ -45,55 +96,298 @@ pub enum Token {
     #[token("=")]
     Assignement,
 
-    // tested 
-    #[regex(r"[0-9]+", Token::decimal)]
-    #[regex("0x[a-fA-F0-9]+", Token::hexadecimal)]
-    #[regex("0b(0|1)+", Token::binary)]
+    // tested
+    // letters are matched so that an invalid digit is reported,
+    // `_` separates the digits
+    #[regex(r"[0-9][0-9a-zA-Z_]*", Token::number)]
+    #[regex(r"'([^'\\\n]|\\[^\n][0-9a-fA-F]*)'", Token::character)]
     Value(u16),
+    /// `1b` or `1f`, the closest numeric label `1:` before or after
+    // tested
+    #[regex(r"[0-9]+[bf]", Token::reference, priority = 3)]
+    Reference(Reference),
 
     // tested
     #[token(":", Dir::new)]
     #[token("DEFINE", Dir::new)]
+    #[token("MACRO", Dir::new)]
+    #[token("ENDM", Dir::new)]
+    #[token("IF", Dir::new)]
+    #[token("ELSEIF", Dir::new)]
+    #[token("ELSE", Dir::new)]
+    #[token("ENDIF", Dir::new)]
+    #[token("IFDEF", Dir::new)]
+    #[token("IFNDEF", Dir::new)]
+    #[token("INCLUDE", Dir::new)]
+    #[token("REPT", Dir::new)]
+    #[token("ENDR", Dir::new)]
+    #[token("FOR", Dir::new)]
+    #[token("TO", Dir::new)]
+    #[token("ENDF", Dir::new)]
+    #[token("SET", Dir::new)]
+    #[token(".word", Dir::new)]
+    #[token(".fill", Dir::new)]
+    #[token(".zero", Dir::new)]
+    #[token("ORG", Dir::new)]
+    #[token(".section", Dir::new)]
+    #[token(".text", Dir::new)]
+    #[token(".data", Dir::new)]
+    #[token(".entry", Dir::new)]
+    #[token(".string", Dir::new)]
+    #[token(".stringz", Dir::new)]
+    #[regex(r"\.arch[ \t]+[a-zA-Z0-9_]+", Dir::new)]
     Directive(Dir),
 
-    // Register has a higher priority than Identifier
+    // Register has a higher priority than Identifier, the other
+    // single letters are names, the registers named by an ISA file
+    // are read by the parser, except `*B` which can't be a name
     // tested
-    #[regex(r"\*?[A-Z]", Reg::new, priority = 2)]
+    #[regex(r"\*[A-Z][a-zA-Z0-9_]*|[AVDZO]", Reg::new, priority = 3)]
     Register(Reg),
-    // tested 
-    #[regex(r"[a-z_A-Z]+", Token::text, priority = 1)]
+    // `.name` is a local label
+    // tested
+    #[regex(r"\.?[a-zA-Z_][a-zA-Z0-9_.]*", Token::text, priority = 1)]
     Identifier(String),
 
+    // operators of constant expressions, the other ones are Operation tokens
+    // tested
+    #[token("*")]
+    Star,
+    #[token("/")]
+    Slash,
+    #[token("%")]
+    Percent,
+    #[token("<<")]
+    ShiftLeft,
+    #[token(">>")]
+    ShiftRight,
+    #[token("(")]
+    OpenParen,
+    #[token(")")]
+    CloseParen,
+    /// address of the current instruction
+    #[token("$")]
+    Here,
+    /// separates the parameters of a macro
+    #[token(",")]
+    Comma,
+    /// `"path.asm"`, without the quotes and with the escapes replaced
+    // tested
+    #[regex(r#""([^"\\\n]|\\[^\n])*""#, Token::string)]
+    Str(String),
+
     // No test
     #[regex(r";[^\n]*")]
     Comment,
+
+    // statements end with a newline
+    // tested
+    #[token("\n")]
+    Newline,
 }
 
 impl Token {
-    parse_number!(decimal, "", 10);
-    parse_number!(hexadecimal, "0x", 16);
-    parse_number!(binary, "0b", 2);
+    fn number(lex: &mut Lexer<Token>) -> Result<u16, LexError> {
+        literal(lex.slice())
+    }
+
+    fn reference(lex: &mut Lexer<Token>) -> Result<Reference, LexError> {
+        let slice = lex.slice();
+        let (digits, direction) = slice.split_at(slice.len() - 1);
+        match digits.parse() {
+            Ok(label) => Ok(Reference {
+                label,
+                forward: direction == "f",
+            }),
+            Err(_) => Err(LexError::ValueOutOfRange { max: u16::MAX }),
+        }
+    }
 
     fn text(lex: &mut Lexer<Token>) -> Option<String> {
         Some(lex.slice().to_string())
     }
+
+    fn string(lex: &mut Lexer<Token>) -> Result<String, LexError> {
+        let slice = lex.slice();
+        unescape(&slice[1..slice.len() - 1])
+    }
+
+    /// `'A'` character literal, its code is the value
+    fn character(lex: &mut Lexer<Token>) -> Result<u16, LexError> {
+        let slice = lex.slice();
+        let text = unescape(&slice[1..slice.len() - 1])?;
+        let mut chars = text.chars();
+        match (chars.next(), chars.next()) {
+            (Some(c), None) if (c as u32) <= 0xff => Ok(c as u16),
+            (Some(_), None) => Err(LexError::CharacterOutOfRange),
+            // `\x` followed by too many digits
+            _ => Err(LexError::UnknownEscape),
+        }
+    }
+}
+
+/// decimal, 0x hexadecimal, 0o octal or 0b binary number, `_` can separate the digits
+pub fn literal(text: &str) -> Result<u16, LexError> {
+    let text = text.replace('_', "");
+    let (digits, radix) = if let Some(hex) = text.strip_prefix("0x") {
+        (hex, 16)
+    } else if let Some(oct) = text.strip_prefix("0o") {
+        (oct, 8)
+    } else if let Some(bin) = text.strip_prefix("0b") {
+        (bin, 2)
+    } else {
+        (text.as_str(), 10)
+    };
+
+    // whether a load can hold the value is checked by the parser,
+    // data words take 16 bits
+    match u16::from_str_radix(digits, radix) {
+        Ok(n) => Ok(n),
+        Err(e) if *e.kind() != std::num::IntErrorKind::PosOverflow => {
+            Err(LexError::InvalidDigit { radix })
+        }
+        _ => Err(LexError::ValueOutOfRange { max: u16::MAX }),
+    }
+}
+
+/// parse a number written like in the source: decimal, 0x, 0o or 0b with `_`
+/// between the digits, a negative one is stored in two's complement
+pub fn parse_number(s: &str) -> Result<u16, String> {
+    let (negative, digits) = match s.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, s),
+    };
+    if !digits.starts_with(|c: char| c.is_ascii_digit()) {
+        return Err(format!("{s}: expected a number"));
+    }
+    let out_of_range = || format!("{s}: the value must be between -32768 and 65535");
+    match literal(digits) {
+        Ok(val) if !negative => Ok(val),
+        Ok(val) if val <= 0x8000 => Ok(val.wrapping_neg()),
+        Ok(_) | Err(LexError::ValueOutOfRange { .. }) => Err(out_of_range()),
+        Err(LexError::InvalidDigit { radix }) => {
+            Err(format!("{s}: invalid digit in a base {radix} number"))
+        }
+        Err(_) => Err(format!("{s}: expected a number")),
+    }
+}
+
+/// replace the escape sequences of a string or character literal
+fn unescape(text: &str) -> Result<String, LexError> {
+    let mut unescaped = String::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        let escaped = match chars.next() {
+            Some('n') => '\n',
+            Some('r') => '\r',
+            Some('t') => '\t',
+            Some('0') => '\0',
+            Some(c @ ('\\' | '\'' | '"')) => c,
+            Some('x') => {
+                let digits: String = chars.by_ref().take(2).collect();
+                match u8::from_str_radix(&digits, 16) {
+                    Ok(code) if digits.len() == 2 => code as char,
+                    _ => return Err(LexError::UnknownEscape),
+                }
+            }
+            _ => return Err(LexError::UnknownEscape),
+        };
+        unescaped.push(escaped);
+    }
+    Ok(unescaped)
 }
 
-#[derive(Debug, PartialEq)]
+/// Reference to a numeric label
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Reference {
+    pub label: u16,
+    /// whether it is the next definition instead of the previous one
+    pub forward: bool,
+}
+
+impl std::fmt::Display for Reference {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let direction = if self.forward { "f" } else { "b" };
+        write!(f, "{}{direction}", self.label)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum Dir {
     Define,
     Label,
+    Macro,
+    EndMacro,
+    If,
+    ElseIf,
+    Else,
+    EndIf,
+    IfDef,
+    IfNotDef,
+    Include,
+    Repeat,
+    EndRepeat,
+    For,
+    To,
+    EndFor,
+    Set,
+    Word,
+    Fill,
+    Zero,
+    Org,
+    Section,
+    Text,
+    Data,
+    Entry,
+    String,
+    StringZero,
+    Arch(String),
 }
 
 impl HandleToken for Dir {
-    fn new(lex: &mut Lexer<Token>) -> Option<Self>
+    fn new(lex: &mut Lexer<Token>) -> Result<Self, LexError>
     where
         Self: Sized,
     {
         match lex.slice() {
-            "DEFINE" => Some(Dir::Define),
-            ":" => Some(Dir::Label),
-            _ => None,
+            "DEFINE" => Ok(Dir::Define),
+            "MACRO" => Ok(Dir::Macro),
+            "ENDM" => Ok(Dir::EndMacro),
+            "IF" => Ok(Dir::If),
+            "ELSEIF" => Ok(Dir::ElseIf),
+            "ELSE" => Ok(Dir::Else),
+            "ENDIF" => Ok(Dir::EndIf),
+            "IFDEF" => Ok(Dir::IfDef),
+            "IFNDEF" => Ok(Dir::IfNotDef),
+            "INCLUDE" => Ok(Dir::Include),
+            "REPT" => Ok(Dir::Repeat),
+            "ENDR" => Ok(Dir::EndRepeat),
+            "FOR" => Ok(Dir::For),
+            "TO" => Ok(Dir::To),
+            "ENDF" => Ok(Dir::EndFor),
+            "SET" => Ok(Dir::Set),
+            ".word" => Ok(Dir::Word),
+            ".fill" => Ok(Dir::Fill),
+            ".zero" => Ok(Dir::Zero),
+            "ORG" => Ok(Dir::Org),
+            ".section" => Ok(Dir::Section),
+            ".text" => Ok(Dir::Text),
+            ".data" => Ok(Dir::Data),
+            ".entry" => Ok(Dir::Entry),
+            ".string" => Ok(Dir::String),
+            ".stringz" => Ok(Dir::StringZero),
+            ":" => Ok(Dir::Label),
+            // the targeted architecture is switched by the parser,
+            // only when the directive is assembled
+            arch if arch.starts_with(".arch") => {
+                let name = arch.trim_start_matches(".arch").trim();
+                Ok(Dir::Arch(name.to_string()))
+            }
+            _ => Err(LexError::UnexpectedCharacter),
         }
     }
 }
@@ -164,10 +458,14 @@ mod tests {
         lex.next();
         assert_eq!(lex.next(), Some(Ok(Token::Register(Reg::AStar))));
 
-        // Register B doesn't exist
-        // TODO: return a specific error with helper including the list of valid registers
-        let mut lex = Token::lexer("B");
-        assert_eq!(lex.next(), Some(Err(())));
+        // *B can only be a register of an ISA file, B alone is a name
+        // until the parser knows the architecture
+        let mut lex = Token::lexer("*B B *A_VERY_LONG_NAME");
+        let named = Reg::Named(Name::new("*B").unwrap());
+        assert_eq!(lex.next(), Some(Ok(Token::Register(named))));
+        assert_eq!(lex.next(), Some(Ok(Token::Identifier("B".to_string()))));
+        let valid = ["A", "V", "*A", "*V", "D", "Z", "O"].map(String::from).to_vec();
+        assert_eq!(lex.next(), Some(Err(LexError::UnknownRegister { valid })));
     }
 
     #[test]
@@ -197,12 +495,91 @@ mod tests {
         assert_eq!(lex.next(), Some(Ok(Token::Directive(Dir::Define))));
         lex.next();
         lex.next();
+        assert_eq!(lex.next(), Some(Ok(Token::Newline)));
         lex.next();
         assert_eq!(lex.next(), Some(Ok(Token::Directive(Dir::Label))));
 
         let mut lex = Token::lexer("DEFINE:\n");
         assert_eq!(lex.next(), Some(Ok(Token::Directive(Dir::Define))));
         assert_eq!(lex.next(), Some(Ok(Token::Directive(Dir::Label))));
+
+        let mut lex = Token::lexer(".arch v1\n.arch  v42");
+        assert_eq!(lex.next(), Some(Ok(Token::Directive(Dir::Arch("v1".to_string())))));
+        assert_eq!(lex.next(), Some(Ok(Token::Newline)));
+        assert_eq!(lex.next(), Some(Ok(Token::Directive(Dir::Arch("v42".to_string())))));
+
+        let mut lex = Token::lexer("MACRO check mask, reg
+ENDM");
+        assert_eq!(lex.next(), Some(Ok(Token::Directive(Dir::Macro))));
+        assert_eq!(lex.next(), Some(Ok(Token::Identifier("check".to_string()))));
+        lex.next();
+        assert_eq!(lex.next(), Some(Ok(Token::Comma)));
+        lex.next();
+        assert_eq!(lex.next(), Some(Ok(Token::Newline)));
+        assert_eq!(lex.next(), Some(Ok(Token::Directive(Dir::EndMacro))));
+
+        let mut lex = Token::lexer("INCLUDE \"io.asm\"\n\"unterminated");
+        assert_eq!(lex.next(), Some(Ok(Token::Directive(Dir::Include))));
+        assert_eq!(lex.next(), Some(Ok(Token::Str("io.asm".to_string()))));
+        assert_eq!(lex.next(), Some(Ok(Token::Newline)));
+        assert_eq!(lex.next(), Some(Err(LexError::UnexpectedCharacter)));
+
+        let mut lex = Token::lexer("REPT ENDR FOR TO ENDF SET");
+        assert_eq!(lex.next(), Some(Ok(Token::Directive(Dir::Repeat))));
+        assert_eq!(lex.next(), Some(Ok(Token::Directive(Dir::EndRepeat))));
+        assert_eq!(lex.next(), Some(Ok(Token::Directive(Dir::For))));
+        assert_eq!(lex.next(), Some(Ok(Token::Directive(Dir::To))));
+        assert_eq!(lex.next(), Some(Ok(Token::Directive(Dir::EndFor))));
+        assert_eq!(lex.next(), Some(Ok(Token::Directive(Dir::Set))));
+
+        let mut lex = Token::lexer(".string .stringz");
+        assert_eq!(lex.next(), Some(Ok(Token::Directive(Dir::String))));
+        assert_eq!(lex.next(), Some(Ok(Token::Directive(Dir::StringZero))));
+
+        let mut lex = Token::lexer(".word 1, 2\n.fill .zero");
+        assert_eq!(lex.next(), Some(Ok(Token::Directive(Dir::Word))));
+        assert_eq!(lex.next(), Some(Ok(Token::Value(1))));
+        assert_eq!(lex.next(), Some(Ok(Token::Comma)));
+        assert_eq!(lex.next(), Some(Ok(Token::Value(2))));
+        assert_eq!(lex.next(), Some(Ok(Token::Newline)));
+        assert_eq!(lex.next(), Some(Ok(Token::Directive(Dir::Fill))));
+        assert_eq!(lex.next(), Some(Ok(Token::Directive(Dir::Zero))));
+
+        let mut lex = Token::lexer("ORG 0x100\n.section vectors\n.text .data");
+        assert_eq!(lex.next(), Some(Ok(Token::Directive(Dir::Org))));
+        assert_eq!(lex.next(), Some(Ok(Token::Value(0x100))));
+        assert_eq!(lex.next(), Some(Ok(Token::Newline)));
+        assert_eq!(lex.next(), Some(Ok(Token::Directive(Dir::Section))));
+        assert_eq!(lex.next(), Some(Ok(Token::Identifier("vectors".to_string()))));
+        assert_eq!(lex.next(), Some(Ok(Token::Newline)));
+        assert_eq!(lex.next(), Some(Ok(Token::Directive(Dir::Text))));
+        assert_eq!(lex.next(), Some(Ok(Token::Directive(Dir::Data))));
+
+        let mut lex = Token::lexer(".entry start");
+        assert_eq!(lex.next(), Some(Ok(Token::Directive(Dir::Entry))));
+        assert_eq!(lex.next(), Some(Ok(Token::Identifier("start".to_string()))));
+
+        let mut lex = Token::lexer("IFDEF IFNDEF IF ELSEIF ELSE ENDIF IFFY");
+        assert_eq!(lex.next(), Some(Ok(Token::Directive(Dir::IfDef))));
+        assert_eq!(lex.next(), Some(Ok(Token::Directive(Dir::IfNotDef))));
+        assert_eq!(lex.next(), Some(Ok(Token::Directive(Dir::If))));
+        assert_eq!(lex.next(), Some(Ok(Token::Directive(Dir::ElseIf))));
+        assert_eq!(lex.next(), Some(Ok(Token::Directive(Dir::Else))));
+        assert_eq!(lex.next(), Some(Ok(Token::Directive(Dir::EndIf))));
+        assert_eq!(lex.next(), Some(Ok(Token::Identifier("IFFY".to_string()))));
+    }
+
+    #[test]
+    fn test_parse_number() {
+        assert_eq!(Ok(0o17), parse_number("0o17"));
+        assert_eq!(Ok(0x7fff), parse_number("0x7f_ff"));
+        assert_eq!(Ok(0b101), parse_number("0b101"));
+        assert_eq!(Ok(0xffff), parse_number("-1"));
+        assert_eq!(Ok(0x8000), parse_number("-0x8000"));
+        assert!(parse_number("-0x8001").is_err());
+        assert!(parse_number("65536").is_err());
+        assert!(parse_number("0o8").is_err());
+        assert!(parse_number("-").is_err());
     }
 
     #[test]
@@ -218,10 +595,68 @@ mod tests {
         assert_eq!(lex.next(), Some(Ok(Token::Value(1))));
         assert_eq!(lex.next(), Some(Ok(Token::Value(32767))));
 
-        let mut lex = Token::lexer("32768 0x8000 0b1000000000000000");
-        assert_eq!(lex.next(), Some(Err(())));
-        assert_eq!(lex.next(), Some(Err(())));
-        assert_eq!(lex.next(), Some(Err(())));
+        let mut lex = Token::lexer("65535 0xffff 0b1111111111111111");
+        for _ in 0..3 {
+            assert_eq!(lex.next(), Some(Ok(Token::Value(65535))));
+        }
+
+        let mut lex = Token::lexer("65536 0x10000 0b10000000000000000");
+        for _ in 0..3 {
+            assert_eq!(lex.next(), Some(Err(LexError::ValueOutOfRange { max: 65535 })));
+        }
+
+        let mut lex = Token::lexer("12a 0x7g 0b102 0x 0o8 0x_");
+        assert_eq!(lex.next(), Some(Err(LexError::InvalidDigit { radix: 10 })));
+        assert_eq!(lex.next(), Some(Err(LexError::InvalidDigit { radix: 16 })));
+        assert_eq!(lex.next(), Some(Err(LexError::InvalidDigit { radix: 2 })));
+        assert_eq!(lex.next(), Some(Err(LexError::InvalidDigit { radix: 16 })));
+        assert_eq!(lex.next(), Some(Err(LexError::InvalidDigit { radix: 8 })));
+        assert_eq!(lex.next(), Some(Err(LexError::InvalidDigit { radix: 16 })));
+
+        let mut lex = Token::lexer("0o17 0o177777 0x7f_ff 1_000 0b1010_0101");
+        assert_eq!(lex.next(), Some(Ok(Token::Value(0o17))));
+        assert_eq!(lex.next(), Some(Ok(Token::Value(0xffff))));
+        assert_eq!(lex.next(), Some(Ok(Token::Value(0x7fff))));
+        assert_eq!(lex.next(), Some(Ok(Token::Value(1000))));
+        assert_eq!(lex.next(), Some(Ok(Token::Value(0b1010_0101))));
+    }
+
+    #[test]
+    fn test_reference() {
+        let mut lex = Token::lexer("1b 2f 10f 0b 0b1 0x1f");
+        let reference = |label, forward| Some(Ok(Token::Reference(Reference { label, forward })));
+        assert_eq!(lex.next(), reference(1, false));
+        assert_eq!(lex.next(), reference(2, true));
+        assert_eq!(lex.next(), reference(10, true));
+        assert_eq!(lex.next(), reference(0, false));
+        assert_eq!(lex.next(), Some(Ok(Token::Value(1))));
+        assert_eq!(lex.next(), Some(Ok(Token::Value(0x1f))));
+
+        let mut lex = Token::lexer("65536b");
+        assert_eq!(
+            lex.next(),
+            Some(Err(LexError::ValueOutOfRange { max: 65535 }))
+        );
+    }
+
+    #[test]
+    fn test_characters() {
+        let mut lex = Token::lexer(r#"'A' ' ' '\n' '\'' '\\' '\x7f' '\xff' "a\"b\t\x41""#);
+        assert_eq!(lex.next(), Some(Ok(Token::Value(65))));
+        assert_eq!(lex.next(), Some(Ok(Token::Value(32))));
+        assert_eq!(lex.next(), Some(Ok(Token::Value(10))));
+        assert_eq!(lex.next(), Some(Ok(Token::Value(39))));
+        assert_eq!(lex.next(), Some(Ok(Token::Value(92))));
+        assert_eq!(lex.next(), Some(Ok(Token::Value(0x7f))));
+        assert_eq!(lex.next(), Some(Ok(Token::Value(0xff))));
+        assert_eq!(lex.next(), Some(Ok(Token::Str("a\"b\tA".to_string()))));
+
+        let mut lex = Token::lexer(r#"'\q' '\x4' '\x414' '€' "\z""#);
+        for _ in 0..3 {
+            assert_eq!(lex.next(), Some(Err(LexError::UnknownEscape)));
+        }
+        assert_eq!(lex.next(), Some(Err(LexError::CharacterOutOfRange)));
+        assert_eq!(lex.next(), Some(Err(LexError::UnknownEscape)));
     }
 
     #[test]
@@ -230,9 +665,10 @@ mod tests {
         lex.next();
         assert_eq!(lex.next(), Some(Ok(Token::Identifier("id".to_string()))));
         lex.next();
+        lex.next();
         assert_eq!(lex.next(), Some(Ok(Token::Identifier("id".to_string()))));
 
-        let test_string = "a b c foo bar FOO BAR foo_bar FOO_BAR Foo_Bar";
+        let test_string = "a b c foo bar FOO BAR foo_bar FOO_BAR Foo_Bar loop1 state_2 motor.left .done X AD JMPS";
         let string_iter = test_string.split(" ");
         let mut lex = Token::lexer(test_string);
 
@@ -245,10 +681,60 @@ mod tests {
     fn test_comment() {
         let mut lex = Token::lexer("; this is a comment\nD ; comment\n;;;;\n;comm\n");
         assert_eq!(lex.next(), Some(Ok(Token::Comment)));
+        assert_eq!(lex.next(), Some(Ok(Token::Newline)));
         lex.next();
         assert_eq!(lex.next(), Some(Ok(Token::Comment)));
+        assert_eq!(lex.next(), Some(Ok(Token::Newline)));
         assert_eq!(lex.next(), Some(Ok(Token::Comment)));
+        assert_eq!(lex.next(), Some(Ok(Token::Newline)));
         assert_eq!(lex.next(), Some(Ok(Token::Comment)));
+        assert_eq!(lex.next(), Some(Ok(Token::Newline)));
+        assert_eq!(lex.next(), None);
+    }
+
+    #[test]
+    fn test_expression() {
+        let mut lex = Token::lexer("($ - start) * 2 / 4 % 3 << 1 >> 2 | ~mask *A");
+        let expected = [
+            Token::OpenParen,
+            Token::Here,
+            Token::Operation(Op::Sub),
+            Token::Identifier("start".to_string()),
+            Token::CloseParen,
+            Token::Star,
+            Token::Value(2),
+            Token::Slash,
+            Token::Value(4),
+            Token::Percent,
+            Token::Value(3),
+            Token::ShiftLeft,
+            Token::Value(1),
+            Token::ShiftRight,
+            Token::Value(2),
+            Token::Operation(Op::Or),
+            Token::Operation(Op::Not),
+            Token::Identifier("mask".to_string()),
+            Token::Register(Reg::AStar),
+        ];
+        for token in expected {
+            assert_eq!(lex.next(), Some(Ok(token)));
+        }
+
+        // shifts are longer than conditions
+        let mut lex = Token::lexer("<< < >>= >");
+        assert_eq!(lex.next(), Some(Ok(Token::ShiftLeft)));
+        assert_eq!(lex.next(), Some(Ok(Token::Condition(Cond::Lt))));
+        assert_eq!(lex.next(), Some(Ok(Token::ShiftRight)));
+        assert_eq!(lex.next(), Some(Ok(Token::Assignement)));
+        assert_eq!(lex.next(), Some(Ok(Token::Condition(Cond::Gt))));
+    }
+
+    #[test]
+    fn test_unexpected_character() {
+        let mut lex = Token::lexer("A ? D").spanned();
+        lex.next();
+        assert_eq!(lex.next(), Some((Err(LexError::UnexpectedCharacter), 2..3)));
+        assert_eq!(lex.next(), Some((Ok(Token::Register(Reg::D)), 4..5)));
     }
 
     #[test]
@@ -256,9 +742,11 @@ mod tests {
         let mut lex = Token::lexer("move:\nA = move_mask\nD = A\n");
         assert_eq!(lex.next(), Some(Ok(Token::Identifier("move".to_string()))));
         assert_eq!(lex.next(), Some(Ok(Token::Directive(Dir::Label))));
+        assert_eq!(lex.next(), Some(Ok(Token::Newline)));
         assert_eq!(lex.next(), Some(Ok(Token::Register(Reg::A))));
         assert_eq!(lex.next(), Some(Ok(Token::Assignement)));
         assert_eq!(lex.next(), Some(Ok(Token::Identifier("move_mask".to_string()))));
+        assert_eq!(lex.next(), Some(Ok(Token::Newline)));
         assert_eq!(lex.next(), Some(Ok(Token::Register(Reg::D))));
         assert_eq!(lex.next(), Some(Ok(Token::Assignement)));
         assert_eq!(lex.next(), Some(Ok(Token::Register(Reg::A))));
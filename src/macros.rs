@@ -0,0 +1,87 @@
+use crate::ast::{Expansion, Spanned};
+use crate::lexer::{Dir, LexError, Token};
+use crate::source::with_labels;
+use miette::{Error, LabeledSpan};
+use std::ops::Range;
+
+pub type SpannedToken = (Result<Token, LexError>, Range<usize>);
+
+/// `MACRO name param1, param2` ... `ENDM`, the body is parsed at each call
+#[derive(Debug, Clone)]
+pub struct Macro {
+    pub name: Spanned<String>,
+    pub params: Vec<Spanned<String>>,
+    /// tokens between the definition line and `ENDM`, without the last newline
+    pub body: Vec<SpannedToken>,
+}
+
+impl Macro {
+    /// identifiers declared as labels in the body
+    fn locals(&self) -> Vec<&str> {
+        self.body
+            .windows(2)
+            .filter_map(|pair| match pair {
+                [(Ok(Token::Identifier(id)), _), (Ok(Token::Directive(Dir::Label)), _)] => {
+                    Some(id.as_str())
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// body of the `count`th expansion, the arguments replace the parameters
+    /// and take their span, the local labels get a `#count` suffix
+    pub fn expand(&self, args: &[Vec<SpannedToken>], count: usize) -> Vec<SpannedToken> {
+        let locals = self.locals();
+        let mut tokens = vec![];
+
+        for (token, span) in &self.body {
+            let Ok(Token::Identifier(id)) = token else {
+                tokens.push((token.clone(), span.clone()));
+                continue;
+            };
+            match self.params.iter().position(|param| param.node == *id) {
+                Some(index) => tokens.extend(
+                    args[index]
+                        .iter()
+                        .map(|(arg, _)| (arg.clone(), span.clone())),
+                ),
+                None if locals.contains(&id.as_str()) => {
+                    tokens.push((Ok(Token::Identifier(format!("{id}#{count}"))), span.clone()))
+                }
+                None => tokens.push((token.clone(), span.clone())),
+            }
+        }
+
+        tokens
+    }
+}
+
+/// name of an identifier as written in the source, before the
+/// renaming of the labels local to a macro
+pub fn source_name(id: &str) -> &str {
+    id.split('#').next().unwrap_or(id)
+}
+
+/// label the calls which expanded into the source of an error, innermost first
+pub fn within(error: Error, calls: &[&Expansion]) -> Error {
+    let calls = calls
+        .iter()
+        .map(|expansion| {
+            LabeledSpan::at(
+                expansion.call.clone(),
+                format!("in this expansion of {}", expansion.name),
+            )
+        })
+        .collect();
+    with_labels(error, calls)
+}
+
+/// label the calls in the errors pushed since `from`
+pub fn trace(errors: &mut Vec<Error>, from: usize, calls: &[&Expansion]) {
+    if calls.is_empty() {
+        return;
+    }
+    let traced: Vec<Error> = errors.drain(from..).map(|e| within(e, calls)).collect();
+    errors.extend(traced);
+}
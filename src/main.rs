@@ -1,32 +1,65 @@
+mod ast;
+mod debugger;
+mod disasm;
+mod eval;
 mod lexer;
+mod macros;
 mod parser;
+mod render;
+mod sections;
+mod sim;
+mod source;
 
 use std::fs::File;
 
-use clap::Parser;
+use clap::{Args, Parser, Subcommand};
 use colored::Colorize;
-use lexer::Token;
-use logos::Logos;
+use lexer::spec::{isa, Architecture, Targets};
+use lexer::parse_number;
 use miette::Severity;
+use source::SourceMap;
 
 /// Simple cli to parse and generate bit stream for my custom assembly language
 #[derive(Parser)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Cli {
-    /// assembly file path
-    file_path: String,
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[command(flatten)]
+    source: SourceArgs,
 
     /// whether to colorize the bit stream output
     #[arg(short = 'c', long = "color")]
     color: bool,
 
-    /// whether to print debug messages
-    #[arg(short = 'd', long = "debug")]
-    debug: bool,
-
     /// separator between each words in the bit stream
     #[arg(short = 's', long = "sep", default_value_t = String::from(""))]
     sep: String,
 
+    /// save output in designated file
+    #[arg(short = 'o', long = "output")]
+    output_path: Option<String>,
+
+    /// save a listing of the address, the word and the source of each instruction in designated file
+    #[arg(short = 'l', long = "listing")]
+    listing_path: Option<String>,
+
+    /// output raw big endian words instead of a bit stream
+    #[arg(short = 'b', long = "binary")]
+    binary: bool,
+}
+
+#[derive(Args)]
+struct SourceArgs {
+    /// assembly file path
+    #[arg(required = true)]
+    file_path: Option<String>,
+
+    /// whether to print debug messages
+    #[arg(short = 'd', long = "debug")]
+    debug: bool,
+
     /// whether to turn off warnings
     #[arg(long = "w-off")]
     warning_off: bool,
@@ -35,76 +68,397 @@ struct Cli {
     #[arg(short = 'W', long = "Warn")]
     warning: bool,
 
-    /// save output in designated file
-    #[arg(short = 'o', long = "output")]
-    output_path: Option<String>,
+    /// constant read by the IF directives and the program, NAME alone is 1
+    #[arg(short = 'D', long = "define", value_name = "NAME[=VALUE]", value_parser = parse_define)]
+    defines: Vec<(String, u16)>,
+
+    /// directory searched for the INCLUDE files, after the one of the including file
+    #[arg(short = 'I', long = "include", value_name = "DIR")]
+    include_paths: Vec<String>,
+
+    /// load the base address of the sections from a layout file
+    #[arg(short = 'L', long = "layout")]
+    layout_path: Option<String>,
+
+    #[command(flatten)]
+    target: TargetArgs,
 }
 
-fn main() {
-    use std::io::Read;
-    use std::io::Write;
+#[derive(Args)]
+struct TargetArgs {
+    /// targeted architecture, overridden by an `.arch` directive in the source
+    #[arg(short = 'a', long = "arch", default_value_t = String::from("v1"))]
+    arch: String,
 
-    let args = Cli::parse();
+    /// load an architecture from an ISA description file
+    #[arg(short = 'i', long = "isa")]
+    isa_paths: Vec<String>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// translate the output of the assembler back into assembly source
+    Disasm {
+        /// bit stream or binary file path
+        file_path: String,
+
+        /// whether the file holds raw big endian words instead of a bit stream
+        #[arg(short = 'b', long = "binary")]
+        binary: bool,
 
-    if let Ok(mut file) = std::fs::File::open(args.file_path.clone()) {
-        let mut content = String::new();
-        let _ = file.read_to_string(&mut content);
+        /// save output in designated file
+        #[arg(short = 'o', long = "output")]
+        output_path: Option<String>,
 
-        let lex = Token::lexer(content.as_str());
+        #[command(flatten)]
+        target: TargetArgs,
+    },
+    /// assemble a program and execute it on the simulator
+    Run {
+        #[command(flatten)]
+        source: SourceArgs,
 
-        let mut tokens: Vec<(Result<Token, ()>, std::ops::Range<usize>)> = lex.spanned().collect();
+        /// stop the simulation after this number of instructions
+        #[arg(long = "max-cycles", default_value_t = 10_000)]
+        max_cycles: usize,
 
-        let parser_report = parser::parse(&mut tokens, args.color, args.debug, &args.sep);
+        /// initial memory content, given as ADR=VALUE
+        #[arg(short = 'm', long = "mem", value_parser = parse_memory)]
+        memory: Vec<(u16, u16)>,
+    },
+    /// assemble a program and execute it step by step
+    Debug {
+        #[command(flatten)]
+        source: SourceArgs,
+
+        /// stop the simulation after this number of instructions
+        #[arg(long = "max-cycles", default_value_t = 10_000)]
+        max_cycles: usize,
+
+        /// initial memory content, given as ADR=VALUE
+        #[arg(short = 'm', long = "mem", value_parser = parse_memory)]
+        memory: Vec<(u16, u16)>,
+    },
+}
 
-        let (errors, warnings): (Vec<_>, Vec<_>) = parser_report
-            .report
-            .into_iter()
-            .partition(|r| r.severity() != Some(Severity::Warning));
+fn parse_memory(s: &str) -> Result<(u16, u16), String> {
+    let (adr, val) = s
+        .split_once('=')
+        .ok_or(format!("expected ADR=VALUE, found {s}"))?;
+    Ok((parse_number(adr.trim())?, parse_number(val.trim())?))
+}
 
-        let error_number = errors.len();
-        let warning_number = warnings.len();
+fn parse_define(s: &str) -> Result<(String, u16), String> {
+    let (name, val) = match s.split_once('=') {
+        Some((name, val)) => (name.trim(), parse_number(val.trim())?),
+        None => (s.trim(), 1),
+    };
+    let valid = name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.');
+    if !valid {
+        return Err(format!("{name} isn't a valid identifier"));
+    }
+    Ok((name.to_string(), val))
+}
 
-        if warning_number > 0 && !args.warning_off {
-            for w in warnings {
-                println!("{:?}", w.with_source_code(content.clone()));
+/// load the ISA files and select the targeted architecture
+fn targets(args: &TargetArgs) -> Option<Targets> {
+    let mut targets = Targets::default();
+    for path in &args.isa_paths {
+        match isa::load(path) {
+            Ok(arch) => {
+                let name = arch.name().to_string();
+                if !targets.add(Box::new(arch)) {
+                    println!("Architecture {name} from {path} is already defined, exiting !");
+                    return None;
+                }
+            }
+            Err(errors) => {
+                for e in errors {
+                    println!("{:?}", e);
+                }
+                return None;
             }
         }
+    }
+    if !targets.select(&args.arch) {
+        println!(
+            "Unknown architecture {}, expected one of {}",
+            args.arch,
+            targets.names().join(", ")
+        );
+        return None;
+    }
+    Some(targets)
+}
 
-        if error_number > 0 {
-            for e in errors {
-                println!("{:?}", e.with_source_code(content.clone()));
+fn main() {
+    let args = Cli::parse();
+    colored::control::set_override(args.color);
+
+    match &args.command {
+        Some(Command::Disasm {
+            file_path,
+            binary,
+            output_path,
+            target,
+        }) => disassemble(file_path, *binary, output_path, target),
+        Some(Command::Run {
+            source,
+            max_cycles,
+            memory,
+        }) => run(source, *max_cycles, memory),
+        Some(Command::Debug {
+            source,
+            max_cycles,
+            memory,
+        }) => debug(source, *max_cycles, memory),
+        None => assemble(&args),
+    }
+}
+
+/// lex and parse a source file and print its reports,
+/// returns None if the program can't be used
+fn build(args: &SourceArgs) -> Option<(parser::ParserReport, Targets, SourceMap)> {
+    let mut targets = targets(&args.target)?;
+    let layout = match &args.layout_path {
+        Some(path) => match sections::load(path) {
+            Ok(layout) => layout,
+            Err(e) => {
+                println!("{:?}", e);
+                return None;
             }
+        },
+        None => sections::SectionLayout::default(),
+    };
+    let file_path = args.file_path.clone().unwrap_or_default();
 
-            println!(
-                "{} errors and {} warnings found in {}, exiting !",
-                error_number, warning_number, args.file_path
-            );
-            return;
-        }
+    let Ok(content) = std::fs::read_to_string(&file_path) else {
+        println!("Unable to read {file_path}, exiting !");
+        return None;
+    };
 
-        if warning_number > 0 && !args.warning {
-            println!("{} warnings encountered, exiting !", warning_number);
+    // the included files are lexed by the parser, when they are assembled
+    let mut loader = source::Loader::new(&args.include_paths);
+    let tokens = loader.load(&file_path, content);
+    // the `.arch` directives of the program select the target
+    let mut parser_report = parser::parse(
+        &tokens,
+        &mut loader,
+        &mut targets,
+        &args.defines,
+        &layout,
+        args.debug,
+    );
 
-            return;
+    let sources = loader.sources;
+    let reports = std::mem::take(&mut parser_report.report).into_iter();
+    let (notes, reports): (Vec<_>, Vec<_>) = reports
+        .map(|r| sources.included_from(r))
+        .partition(|r| r.severity() == Some(Severity::Advice));
+    let (errors, warnings): (Vec<_>, Vec<_>) = reports
+        .into_iter()
+        .partition(|r| r.severity() != Some(Severity::Warning));
+
+    let error_number = errors.len();
+    let warning_number = warnings.len();
+
+    // notes are shown with the warnings but don't stop the assembly
+    if !args.warning_off {
+        for w in notes.into_iter().chain(warnings) {
+            println!("{:?}", w.with_source_code(sources.clone()));
         }
+    }
 
-        if args.debug {
-            println!(
-                "{}\n{}\n{}\n{}\n{}\n{}\n",
-                "15 bits value".red(),
-                "op/jump code".blue(),
-                "mode bit".green(),
-                "source A reg".yellow(),
-                "source B reg".purple(),
-                "dest reg".cyan()
-            );
+    if error_number > 0 {
+        for e in errors {
+            println!("{:?}", e.with_source_code(sources.clone()));
         }
 
-        if let Some(path) = args.output_path {
+        println!(
+            "{} errors and {} warnings found in {}, exiting !",
+            error_number, warning_number, file_path
+        );
+        return None;
+    }
+
+    if warning_number > 0 && !args.warning {
+        println!("{} warnings encountered, exiting !", warning_number);
+
+        return None;
+    }
+
+    Some((parser_report, targets, sources))
+}
+
+fn assemble(args: &Cli) {
+    use std::io::Write;
+
+    let Some((parser_report, targets, sources)) = build(&args.source) else {
+        return;
+    };
+    let arch = targets.active();
+
+    if let Some(path) = &args.listing_path {
+        let listing = render::listing(
+            &parser_report.instructions,
+            &parser_report.spans,
+            &parser_report.notes,
+            arch,
+            &sources,
+        );
+        let mut output = File::create(path).unwrap();
+        let _ = writeln!(output, "{}", listing);
+    }
+
+    if args.source.debug {
+        println!(
+            "{}\n{}\n{}\n{}\n{}\n{}\n{}\n",
+            "15 bits value".red(),
+            "op/jump code".blue(),
+            "mode bit".green(),
+            "source A reg".yellow(),
+            "source B reg".purple(),
+            "dest reg".cyan(),
+            "data word".white()
+        );
+    }
+
+    if args.binary {
+        let bytes: Vec<u8> = parser_report
+            .instructions
+            .iter()
+            .flat_map(|inst| inst.encode(arch).to_be_bytes())
+            .collect();
+
+        if let Some(path) = &args.output_path {
             let mut output = File::create(path).unwrap();
-            let _ = write!(output, "{}", parser_report.bit_stream);
+            let _ = output.write_all(&bytes);
         } else {
-            println!("{}", parser_report.bit_stream);
+            let _ = std::io::stdout().write_all(&bytes);
+        }
+        return;
+    }
+
+    let bit_stream = render::bit_stream(&parser_report.instructions, arch, &args.sep);
+
+    if let Some(path) = &args.output_path {
+        let mut output = File::create(path).unwrap();
+        let _ = write!(output, "{}", bit_stream);
+    } else {
+        println!("{}", bit_stream);
+    }
+}
+
+fn run(args: &SourceArgs, max_cycles: usize, memory: &[(u16, u16)]) {
+    let Some((parser_report, targets, _)) = build(args) else {
+        return;
+    };
+
+    let mut machine = sim::Machine::new(parser_report.instructions, targets.active());
+    machine.memory.extend(memory.iter().copied());
+
+    let stop = machine.run(max_cycles);
+
+    println!("stopped after {} cycles: {}", machine.cycles, stop);
+    print!("{}", machine);
+}
+
+fn debug(args: &SourceArgs, max_cycles: usize, memory: &[(u16, u16)]) {
+    use std::io::{BufRead, Write};
+
+    let Some((parser_report, targets, sources)) = build(args) else {
+        return;
+    };
+
+    let mut machine = sim::Machine::new(parser_report.instructions, targets.active());
+    machine.memory.extend(memory.iter().copied());
+
+    let mut debugger = debugger::Debugger::new(
+        machine,
+        parser_report.spans,
+        parser_report.id_collect,
+        sources,
+        max_cycles,
+    );
+
+    println!("{}", debugger.execute("list").unwrap_or_default());
+
+    // an empty line repeats the last command
+    let mut last_command = String::from("help");
+    let mut lines = std::io::stdin().lock().lines();
+    loop {
+        print!("(debug) ");
+        let _ = std::io::stdout().flush();
+
+        let Some(Ok(line)) = lines.next() else {
+            return;
+        };
+        if !line.trim().is_empty() {
+            last_command = line;
+        }
+
+        match debugger.execute(&last_command) {
+            Some(output) => println!("{output}"),
+            None => return,
         }
     }
 }
+
+fn disassemble(file_path: &str, binary: bool, output_path: &Option<String>, target: &TargetArgs) {
+    use std::io::Write;
+
+    let Some(targets) = targets(target) else {
+        return;
+    };
+    let arch = targets.active();
+
+    let Ok(bytes) = std::fs::read(file_path) else {
+        println!("Unable to read {file_path}, exiting !");
+        return;
+    };
+    // reports on a bit stream are shown in its text
+    let content = String::from_utf8_lossy(&bytes).to_string();
+
+    let words = match binary {
+        true => disasm::read_binary(&bytes),
+        false => disasm::read_bit_stream(&content),
+    };
+    let words = match words {
+        Ok(words) => words,
+        Err(e) => {
+            println!("{:?}", e.with_source_code(content));
+            return;
+        }
+    };
+
+    let (source, errors) = disasm::disassemble(&words, arch);
+
+    let error_number = errors.len();
+    for e in errors {
+        match binary {
+            true => println!("{}", e),
+            false => println!("{:?}", e.with_source_code(content.clone())),
+        }
+    }
+    if error_number > 0 {
+        println!("{} words can't be decoded", error_number);
+    }
+
+    if let Some(path) = output_path {
+        let mut output = File::create(path).unwrap();
+        let _ = writeln!(output, "{}", source);
+    } else {
+        println!("{}", source);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_define() {
+        assert_eq!(Ok(("BOARD".to_string(), 0xfffe)), parse_define("BOARD=-2"));
+    }
+}
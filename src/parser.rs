@@ -1,4 +1,9 @@
-use crate::lexer::spec::arch_v1::*;
+use crate::ast::{BinOp, Expansion, Expr, Program, Spanned, Statement, UnaryOp};
+use crate::eval::{eval, out_of_range, out_of_word, unknown_reports, word, EvalError};
+use crate::macros::{source_name, trace, within, Macro, SpannedToken};
+use crate::sections::SectionLayout;
+use crate::source::Loader;
+use crate::lexer::spec::*;
 use crate::lexer::{Token::*, *};
 use miette::{miette, Error, LabeledSpan, Severity};
 use std::collections::HashMap;
@@ -6,331 +11,2445 @@ use std::ops::Range;
 
 #[derive(PartialEq, Debug, Clone)]
 pub struct ColType {
-    val: u16,
+    pub val: u16,
+    pub span: Range<usize>,
+    pub visited: bool,
+}
+
+/// tokens that can begin a statement
+const STATEMENT_START: [&str; 19] = [
+    "register",
+    "JMP",
+    "label",
+    "DEFINE",
+    ".arch",
+    "MACRO",
+    "macro call",
+    "IF",
+    "REPT",
+    "FOR",
+    "SET",
+    ".word",
+    ".fill",
+    ".zero",
+    "ORG",
+    ".section",
+    ".entry",
+    ".string",
+    ".stringz",
+];
+
+/// tokens that can begin an expression
+const EXPRESSION_START: [&str; 7] = [
+    "value",
+    "identifier",
+    "reference",
+    "`$`",
+    "`(`",
+    "`-`",
+    "`~`",
+];
+
+/// calls of a macro from its own body are stopped past this depth
+const MAX_EXPANSION_DEPTH: usize = 64;
+
+/// number of addresses, they are 16 bits
+const ADDRESS_SPACE: usize = 0x10000;
+
+/// iterations of a REPT or FOR block
+const MAX_ITERATIONS: i64 = 0xffff;
+
+/// layouts of a program before its loads are given up sizing
+const MAX_LAYOUTS: usize = 16;
+
+/// Load of an expression, patched once every identifier is collected
+#[derive(PartialEq, Debug, Clone)]
+struct Fixup {
+    /// index of the load instruction or data word in the emitted words
+    index: usize,
+    expr: Spanned<Expr>,
+    /// address of the load instruction or data word
+    here: usize,
+    /// index of the statement
+    statement: usize,
+    patch: Patch,
+}
+
+/// words written by a fixup
+#[derive(PartialEq, Debug, Clone, Copy)]
+enum Patch {
+    /// a data mode word
+    Load,
+    /// the two instructions loading a value bigger than a data mode word
+    WideLoad,
+    /// a whole data word
+    Data,
+    /// nothing, the instruction is known and its identifiers are only read
+    Read,
+}
+
+/// word of the program at its address
+struct Placed {
+    inst: Instruction,
+    /// source of the word
     span: Range<usize>,
-    visited: bool,
+    adr: usize,
+}
+
+pub struct ParserReport {
+    pub instructions: Vec<Instruction>,
+    /// source of each instruction
+    pub spans: Vec<Range<usize>>,
+    pub report: Vec<Error>,
+    pub id_collect: HashMap<String, ColType>,
+    /// note on the instructions a statement is assembled into,
+    /// with the index of the first one, shown by the listing
+    pub notes: Vec<(usize, String)>,
+}
+
+/// name of a token in syntax errors
+fn describe(token: &Token) -> String {
+    match token {
+        Operation(op) => format!("`{op}`"),
+        Condition(cond) => format!("`{cond}`"),
+        Assignement => "`=`".to_string(),
+        Value(val) => format!("value {val}"),
+        Reference(reference) => format!("reference {reference}"),
+        Directive(Dir::Define) => "DEFINE".to_string(),
+        Directive(Dir::Label) => "`:`".to_string(),
+        Directive(Dir::Arch(_)) => ".arch".to_string(),
+        Directive(Dir::Macro) => "MACRO".to_string(),
+        Directive(Dir::EndMacro) => "ENDM".to_string(),
+        Directive(Dir::If) => "IF".to_string(),
+        Directive(Dir::ElseIf) => "ELSEIF".to_string(),
+        Directive(Dir::Else) => "ELSE".to_string(),
+        Directive(Dir::EndIf) => "ENDIF".to_string(),
+        Directive(Dir::IfDef) => "IFDEF".to_string(),
+        Directive(Dir::IfNotDef) => "IFNDEF".to_string(),
+        Directive(Dir::Include) => "INCLUDE".to_string(),
+        Directive(Dir::Repeat) => "REPT".to_string(),
+        Directive(Dir::EndRepeat) => "ENDR".to_string(),
+        Directive(Dir::For) => "FOR".to_string(),
+        Directive(Dir::To) => "TO".to_string(),
+        Directive(Dir::EndFor) => "ENDF".to_string(),
+        Directive(Dir::Set) => "SET".to_string(),
+        Directive(Dir::Word) => ".word".to_string(),
+        Directive(Dir::Fill) => ".fill".to_string(),
+        Directive(Dir::Zero) => ".zero".to_string(),
+        Directive(Dir::Org) => "ORG".to_string(),
+        Directive(Dir::Section) => ".section".to_string(),
+        Directive(Dir::Text) => ".text".to_string(),
+        Directive(Dir::Data) => ".data".to_string(),
+        Directive(Dir::Entry) => ".entry".to_string(),
+        Directive(Dir::String) => ".string".to_string(),
+        Directive(Dir::StringZero) => ".stringz".to_string(),
+        Register(reg) => format!("register {reg}"),
+        Identifier(id) => format!("identifier {id}"),
+        Star => "`*`".to_string(),
+        Slash => "`/`".to_string(),
+        Percent => "`%`".to_string(),
+        ShiftLeft => "`<<`".to_string(),
+        ShiftRight => "`>>`".to_string(),
+        OpenParen => "`(`".to_string(),
+        CloseParen => "`)`".to_string(),
+        Here => "`$`".to_string(),
+        Comma => "`,`".to_string(),
+        Str(text) => format!("string \"{text}\""),
+        Comment => "comment".to_string(),
+        Newline => "end of line".to_string(),
+    }
+}
+
+fn register(token: &Token) -> Option<Reg> {
+    match token {
+        Register(reg) => Some(*reg),
+        _ => None,
+    }
+}
+
+/// register, operation or condition called `id` by the architecture
+fn named(arch: &dyn Architecture, id: &str) -> Option<Token> {
+    let is = |name: String| name == id;
+    let reg = arch.registers().into_iter().find(|reg| is(reg.to_string()));
+    let op = arch.operations().into_iter().find(|op| is(op.to_string()));
+    let cond = arch.conditions().into_iter().find(|cond| is(cond.to_string()));
+    reg.map(Register)
+        .or(op.map(Operation))
+        .or(cond.map(Condition))
+}
+
+fn binary_op(token: &Token) -> Option<BinOp> {
+    match token {
+        Star => Some(BinOp::Mul),
+        Slash => Some(BinOp::Div),
+        Percent => Some(BinOp::Rem),
+        Operation(Op::Add) => Some(BinOp::Add),
+        Operation(Op::Sub) => Some(BinOp::Sub),
+        ShiftLeft => Some(BinOp::Shl),
+        ShiftRight => Some(BinOp::Shr),
+        Condition(Cond::Lt) => Some(BinOp::Lt),
+        Condition(Cond::LtEq) => Some(BinOp::LtEq),
+        Condition(Cond::Gt) => Some(BinOp::Gt),
+        Condition(Cond::GtEq) => Some(BinOp::GtEq),
+        Condition(Cond::Eq) => Some(BinOp::Eq),
+        Condition(Cond::Neq) => Some(BinOp::Neq),
+        Operation(Op::And) => Some(BinOp::And),
+        Operation(Op::Xor) => Some(BinOp::Xor),
+        Operation(Op::Or) => Some(BinOp::Or),
+        _ => None,
+    }
+}
+
+/// word of the language a token is made of, if it could be mistaken for a name
+fn reserved(token: &Token) -> Option<String> {
+    match token {
+        Register(reg) => Some(reg.to_string()),
+        Condition(Cond::Jump) => Some("JMP".to_string()),
+        Operation(Op::Named(name)) | Condition(Cond::Named(name)) => Some(name.to_string()),
+        Directive(Dir::Label | Dir::Arch(_)) => None,
+        Directive(_) => Some(describe(token)),
+        _ => None,
+    }
+}
+
+fn reserved_report(word: &str, span: Range<usize>) -> Error {
+    miette!(
+        labels = vec![LabeledSpan::at(span, "reserved word")],
+        help = "A name is made of letters, digits, `_` and `.`, and doesn't start with a digit",
+        "Error: {word} is reserved and can't be used as a name"
+    )
+}
+
+/// source of an ALU operation, `0` and `1` are read from Z and O
+fn alu_source(token: &Token) -> Option<Reg> {
+    match token {
+        Register(reg) => Some(*reg),
+        Value(0) => Some(Reg::Zero),
+        Value(1) => Some(Reg::One),
+        _ => None,
+    }
+}
+
+fn identifier(token: &Token) -> Option<String> {
+    match token {
+        Identifier(id) => Some(id.clone()),
+        _ => None,
+    }
+}
+
+/// Recursive descent parser of the statements, a line holds an optional label
+/// and a statement, after a syntax error the rest of the line is skipped
+struct Grammar<'a> {
+    tokens: Vec<SpannedToken>,
+    pos: usize,
+    errors: Vec<Error>,
+    macros: HashMap<String, Macro>,
+    /// expansions being parsed, the statements end at the last token
+    /// of the body which is `expanding[i].1`
+    expanding: Vec<(Expansion, usize)>,
+    /// number of expansions so far, it makes the local labels unique
+    count: usize,
+    /// constants known while parsing, read by the conditions
+    symbols: HashMap<String, ColType>,
+    /// names given by `-D` or a DEFINE met so far, for IFDEF
+    defined: Vec<String>,
+    /// IF blocks being parsed, the innermost last
+    blocks: Vec<Block>,
+    /// names created by SET, their value is in `symbols`
+    variables: Vec<String>,
+    /// last global label, the scope of the `.name` local labels
+    scope: String,
+    /// definitions of each numeric label so far, the `k`th definition
+    /// of `n:` is named `n#k`
+    numeric: HashMap<u16, usize>,
+    /// `nf` references with the definition they expect
+    forward: Vec<(crate::lexer::Reference, usize, Range<usize>)>,
+    /// lexer of the files included by the lines assembled
+    loader: Loader,
+    /// architectures a `.arch` can select
+    targets: &'a Targets,
+    /// architecture targeted so far, it names the registers,
+    /// operations and conditions the lexer doesn't know
+    arch: &'a dyn Architecture,
+}
+
+/// `IF` ... `ENDIF` block being parsed
+struct Block {
+    /// IF, IFDEF or IFNDEF directive
+    start: Range<usize>,
+    /// whether the enclosing block is assembled
+    outer: bool,
+    /// whether a branch was already assembled
+    taken: bool,
+    /// whether the current branch is assembled
+    active: bool,
+    /// ELSE directive
+    last: Option<Range<usize>>,
+}
+
+impl Grammar<'_> {
+    fn peek(&self, offset: usize) -> Option<&Result<Token, LexError>> {
+        self.tokens.get(self.pos + offset).map(|(token, _)| token)
+    }
+
+    /// consume the next token if `extract` accepts it, report a syntax error otherwise
+    fn expect<T>(
+        &mut self,
+        expected: &[&str],
+        extract: impl Fn(&Token) -> Option<T>,
+    ) -> Option<Spanned<T>> {
+        if let Some((Ok(token), span)) = self.tokens.get(self.pos) {
+            if let Some(node) = extract(token) {
+                let span = span.clone();
+                self.pos += 1;
+                return Some(Spanned::new(node, span));
+            }
+        }
+        self.error(expected);
+        None
+    }
+
+    /// name declared or tested by a directive, a reserved word gets a report of its own
+    fn name(&mut self, expected: &str) -> Option<Spanned<String>> {
+        if let Some((Ok(token), span)) = self.tokens.get(self.pos) {
+            if let Some(word) = reserved(token) {
+                let report = reserved_report(&word, span.clone());
+                self.report(report);
+                return None;
+            }
+        }
+        self.expect(&[expected], identifier)
+    }
+
+    /// prefix the local labels of the line with the global label before them
+    fn scope_locals(&mut self) {
+        for pos in self.pos..self.tokens.len() {
+            let label = matches!(self.tokens.get(pos + 1), Some((Ok(Directive(Dir::Label)), _)));
+            match &mut self.tokens[pos].0 {
+                Ok(Newline) => break,
+                Ok(Identifier(id)) if id.starts_with('.') => *id = format!("{}{id}", self.scope),
+                // the labels local to a macro expansion don't open a scope
+                Ok(Identifier(id)) if label && !id.contains('#') => self.scope = id.clone(),
+                _ => (),
+            }
+        }
+    }
+
+    /// read the identifiers of the line naming a register, an operation or a
+    /// condition of the targeted architecture, the lexer only knows the ones of `v1`
+    fn read_names(&mut self) {
+        let arch = self.arch;
+        for (token, _) in &mut self.tokens[self.pos..] {
+            match token {
+                Ok(Newline) => break,
+                Ok(Identifier(id)) => {
+                    if let Some(named) = named(arch, id) {
+                        *token = Ok(named);
+                    }
+                }
+                _ => (),
+            }
+        }
+    }
+
+    fn error(&mut self, expected: &[&str]) {
+        let message = match expected {
+            [single] => format!("Error: Expected {single}"),
+            _ => format!("Error: Expected one of {}", expected.join(", ")),
+        };
+        let report = match self.tokens.get(self.pos) {
+            // the lexer error is already reported
+            Some((Err(_), _)) => return,
+            Some((Ok(token), span)) => miette!(
+                labels = vec![LabeledSpan::at(span.clone(), format!("found {}", describe(token)))],
+                "{message}"
+            ),
+            None => {
+                let last = self.tokens.last().map_or(0..0, |(_, span)| span.clone());
+                miette!(
+                    labels = vec![LabeledSpan::at(last, "found end of file after this")],
+                    "{message}"
+                )
+            }
+        };
+        self.report(report);
+    }
+
+    /// push an error, labelling the calls of the macros being expanded
+    fn report(&mut self, error: Error) {
+        let calls: Vec<&Expansion> = self.expanding.iter().rev().map(|(e, _)| e).collect();
+        let error = within(error, &calls);
+        self.errors.push(error);
+    }
+
+    fn end_of_line(&self) -> bool {
+        matches!(self.peek(0), None | Some(Ok(Newline)))
+    }
+
+    /// whether the current line is assembled
+    fn assembling(&self) -> bool {
+        self.blocks.last().is_none_or(|block| block.active)
+    }
+
+    fn program(&mut self) -> (Program, Vec<Error>) {
+        let mut program = Program::default();
+
+        while self.pos < self.tokens.len() {
+            if self.line(&mut program).is_none() {
+                while !self.end_of_line() {
+                    self.pos += 1;
+                }
+            }
+            // newline ending the line
+            self.pos += 1;
+
+            while let Some((_, end)) = self.expanding.last() {
+                if *end >= self.pos {
+                    break;
+                }
+                let (mut expansion, _) = self.expanding.pop().unwrap();
+                expansion.statements.end = program.statements.len();
+                program.expansions.push(expansion);
+            }
+        }
+
+        for block in std::mem::take(&mut self.blocks) {
+            let report = miette!(
+                labels = vec![LabeledSpan::at(block.start, "block starting here")],
+                "Error: Missing ENDIF"
+            );
+            self.report(report);
+        }
+
+        let mut missing = vec![];
+        for (reference, count, span) in std::mem::take(&mut self.forward) {
+            if self.numeric.get(&reference.label).copied().unwrap_or(0) > count {
+                continue;
+            }
+            let report = miette!(
+                labels = vec![LabeledSpan::at(span, "Here")],
+                "Error: No label {}: after this reference",
+                reference.label
+            );
+            self.report(report);
+            missing.push(format!("{}#{count}", reference.label));
+        }
+        // already reported, they aren't unknown identifiers
+        for statement in &mut program.statements {
+            for value in expressions(&mut statement.node) {
+                value
+                    .node
+                    .bind(&|id| missing.iter().any(|name| name == id).then_some(0));
+            }
+        }
+
+        (program, std::mem::take(&mut self.errors))
+    }
+
+    fn line(&mut self, program: &mut Program) -> Option<()> {
+        match self.peek(0) {
+            Some(Ok(Directive(
+                Dir::If | Dir::ElseIf | Dir::Else | Dir::EndIf | Dir::IfDef | Dir::IfNotDef,
+            ))) => return self.conditional(program),
+            _ if !self.assembling() => {
+                self.skip(program);
+                return Some(());
+            }
+            Some(Ok(Directive(Dir::Macro))) => return self.macro_definition(),
+            Some(Ok(Directive(Dir::Include))) => return self.include(program),
+            _ => {
+                self.scope_locals();
+                self.read_names();
+            }
+        }
+
+        match self.peek(0) {
+            Some(Ok(Directive(Dir::Repeat | Dir::For))) => {
+                self.repetition(program)?;
+                // the line goes on with the first copy of the body
+                return self.line(program);
+            }
+            Some(Ok(Directive(Dir::Set))) => return self.set(program),
+            _ => (),
+        }
+
+        while !self.end_of_line() {
+            if let Some(Ok(Identifier(name))) = self.peek(0) {
+                let label = self.peek(1) == Some(&Ok(Directive(Dir::Label)));
+                if self.macros.contains_key(name) && !label {
+                    self.call(program)?;
+                    // the line goes on with the body
+                    self.scope_locals();
+                    self.read_names();
+                    continue;
+                }
+            }
+
+            let start = self.pos;
+            let mut statement = match self.peek(0) {
+                Some(Ok(Directive(Dir::Fill | Dir::Zero))) => self.fill(program)?,
+                Some(Ok(Directive(Dir::Org))) => self.org(program)?,
+                _ => self.statement()?,
+            };
+            let span = self.tokens[start].1.start..self.tokens[self.pos - 1].1.end;
+            let label = matches!(statement, Statement::Label(_));
+            self.bind(&mut statement);
+            if let Statement::Load {
+                value, constant, ..
+            } = &mut statement
+            {
+                *constant = self.known(value);
+            }
+            if let Statement::Define { id, value } = &statement {
+                if let Some(variable) = self
+                    .symbols
+                    .get(&id.node)
+                    .filter(|_| self.variables.contains(&id.node))
+                {
+                    let report = miette!(
+                        labels = vec![
+                            LabeledSpan::at(variable.span.clone(), "previously declared here"),
+                            LabeledSpan::at(id.span.clone(), "declared there"),
+                        ],
+                        "Error: Identifier already declared"
+                    );
+                    self.report(report);
+                    return None;
+                }
+                self.constant(id, value);
+            }
+            program.statements.push(Spanned::new(statement, span));
+
+            // a label can be followed by a statement on the same line
+            if !label {
+                break;
+            }
+        }
+
+        if !self.end_of_line() {
+            self.error(&["end of line"]);
+            return None;
+        }
+        Some(())
+    }
+
+    /// skip the rest of a line which isn't assembled, the identifiers
+    /// it reads don't count as unused
+    fn skip(&mut self, program: &mut Program) {
+        while !self.end_of_line() {
+            if let Some(Ok(Identifier(id))) = self.peek(0) {
+                program.referenced.push(id.clone());
+            }
+            self.pos += 1;
+        }
+    }
+
+    /// value of a DEFINE for the following conditions, when it only
+    /// reads the constants known so far
+    fn constant(&mut self, id: &Spanned<String>, value: &Spanned<Expr>) {
+        self.defined.push(id.node.clone());
+        if value.node.uses_here() {
+            return;
+        }
+        let mut symbols = self.symbols.clone();
+        if let Ok(val @ 0..=0xffff) = eval(value, 0, &mut symbols) {
+            let symbol = ColType {
+                val: val as u16,
+                span: id.span.clone(),
+                visited: false,
+            };
+            self.symbols.entry(id.node.clone()).or_insert(symbol);
+        }
+    }
+
+    /// value of an expression which only reads the constants known so far
+    fn known(&self, value: &Spanned<Expr>) -> Option<u16> {
+        if value.node.uses_here() {
+            return None;
+        }
+        eval(value, 0, &mut self.symbols.clone()).ok().and_then(word)
+    }
+
+    /// `IF expr`, `IFDEF name`, `IFNDEF name`, `ELSEIF expr`, `ELSE` or `ENDIF`
+    fn conditional(&mut self, program: &mut Program) -> Option<()> {
+        let (token, span) = self.tokens[self.pos].clone();
+        self.pos += 1;
+        let outer = self.assembling();
+
+        match token {
+            Ok(Directive(Dir::ElseIf | Dir::Else | Dir::EndIf)) if self.blocks.is_empty() => {
+                let name = describe(token.as_ref().unwrap());
+                let report = miette!(
+                    labels = vec![LabeledSpan::at(span, "Here")],
+                    "Error: {name} without IF"
+                );
+                self.report(report);
+                return None;
+            }
+            Ok(Directive(Dir::ElseIf | Dir::Else)) if self.blocks.last().unwrap().last.is_some() => {
+                let name = describe(token.as_ref().unwrap());
+                let other = self.blocks.last().unwrap().last.clone().unwrap();
+                let report = miette!(
+                    labels = vec![
+                        LabeledSpan::at(other, "ELSE here"),
+                        LabeledSpan::at(span, "Here"),
+                    ],
+                    "Error: {name} after ELSE"
+                );
+                self.report(report);
+                return None;
+            }
+            Ok(Directive(dir @ (Dir::If | Dir::IfDef | Dir::IfNotDef))) => {
+                let active = match outer {
+                    true => self.condition(&dir, program),
+                    false => {
+                        self.skip(program);
+                        Some(false)
+                    }
+                };
+                // after a wrong condition the whole block is skipped
+                self.blocks.push(Block {
+                    start: span,
+                    outer,
+                    taken: active.is_none_or(|active| active),
+                    active: active.unwrap_or(false),
+                    last: None,
+                });
+                active?;
+            }
+            Ok(Directive(Dir::ElseIf)) => {
+                let block = self.blocks.last().unwrap();
+                let active = match block.outer && !block.taken {
+                    true => self.condition(&Dir::If, program),
+                    false => {
+                        self.skip(program);
+                        Some(false)
+                    }
+                };
+                let block = self.blocks.last_mut().unwrap();
+                block.taken |= active.is_none_or(|active| active);
+                block.active = active.unwrap_or(false);
+                active?;
+            }
+            Ok(Directive(Dir::Else)) => {
+                let block = self.blocks.last_mut().unwrap();
+                block.active = block.outer && !block.taken;
+                block.taken = true;
+                block.last = Some(span);
+            }
+            _ => {
+                self.blocks.pop();
+            }
+        }
+
+        if !self.end_of_line() {
+            self.error(&["end of line"]);
+            return None;
+        }
+        Some(())
+    }
+
+    /// whether the branch following a directive is assembled,
+    /// None if the condition is wrong
+    fn condition(&mut self, dir: &Dir, program: &mut Program) -> Option<bool> {
+        if let Dir::IfDef | Dir::IfNotDef = dir {
+            let name = self.name("identifier")?;
+            program.referenced.push(name.node.clone());
+            let defined = self.defined.contains(&name.node) || self.variables.contains(&name.node);
+            return Some(defined == (*dir == Dir::IfDef));
+        }
+
+        let expr = self.expression(0)?;
+        self.constant_value(&expr, "a condition", program)
+            .map(|val| val != 0)
+    }
+
+    /// value of an expression needed while parsing, `what` names it in the reports
+    fn constant_value(
+        &mut self,
+        expr: &Spanned<Expr>,
+        what: &str,
+        program: &mut Program,
+    ) -> Option<i64> {
+        program
+            .referenced
+            .extend(expr.node.identifiers().iter().map(|id| id.to_string()));
+        if expr.node.uses_here() {
+            let report = miette!(
+                labels = vec![LabeledSpan::at(expr.span.clone(), "Here")],
+                help = "The addresses aren't known while parsing",
+                "Error: `$` can't be used in {what}"
+            );
+            self.report(report);
+            return None;
+        }
+
+        match eval(expr, 0, &mut self.symbols) {
+            Ok(val) => Some(val),
+            // only the constants given with -D or defined above can be read
+            Err(EvalError::Unknown(uses)) => {
+                for report in unknown_reports(uses) {
+                    self.report(report);
+                }
+                None
+            }
+            Err(EvalError::Invalid(e)) => {
+                self.report(e);
+                None
+            }
+        }
+    }
+
+    /// `MACRO name param1, param2` up to `ENDM`, the body is kept
+    /// even if the definition line is wrong so that it isn't parsed
+    fn macro_definition(&mut self) -> Option<()> {
+        let keyword = self.tokens[self.pos].1.clone();
+        self.pos += 1;
+        let header = self.macro_header();
+        while !self.end_of_line() {
+            self.pos += 1;
+        }
+
+        let body_start = (self.pos + 1).min(self.tokens.len());
+        let mut end = body_start;
+        loop {
+            match self.tokens.get(end) {
+                Some((Ok(Directive(Dir::EndMacro)), _)) => break,
+                Some((Ok(Directive(Dir::Macro)), span)) => {
+                    let report = miette!(
+                        labels = vec![LabeledSpan::at(span.clone(), "Here")],
+                        help = "Define it after the ENDM of the enclosing macro",
+                        "Error: Macro defined inside another one"
+                    );
+                    self.report(report);
+                }
+                Some(_) => (),
+                None => {
+                    let label = header.as_ref().map_or(keyword, |(name, _)| name.span.clone());
+                    let report = miette!(
+                        labels = vec![LabeledSpan::at(label, "macro starting here")],
+                        "Error: Missing ENDM"
+                    );
+                    self.report(report);
+                    self.pos = end;
+                    return None;
+                }
+            }
+            end += 1;
+        }
+
+        let mut body = self.tokens[body_start..end].to_vec();
+        if let Some((Ok(Newline), _)) = body.last() {
+            body.pop();
+        }
+        // ENDM
+        self.pos = end + 1;
+
+        if let Some((name, params)) = header {
+            match self.macros.get(&name.node) {
+                Some(other) => {
+                    let report = miette!(
+                        labels = vec![
+                            LabeledSpan::at(other.name.span.clone(), "previously defined here"),
+                            LabeledSpan::at(name.span.clone(), "defined there"),
+                        ],
+                        "Error: Macro {} already defined",
+                        name.node
+                    );
+                    self.report(report);
+                }
+                None => {
+                    self.macros.insert(name.node.clone(), Macro { name, params, body });
+                }
+            }
+        }
+
+        if !self.end_of_line() {
+            self.error(&["end of line"]);
+            return None;
+        }
+        Some(())
+    }
+
+    /// name and parameters of a macro
+    fn macro_header(&mut self) -> Option<(Spanned<String>, Vec<Spanned<String>>)> {
+        let name = self.name("identifier")?;
+        let mut params: Vec<Spanned<String>> = vec![];
+
+        while !self.end_of_line() {
+            if !params.is_empty() {
+                self.expect(&["`,`", "end of line"], |token| (*token == Comma).then_some(()))?;
+            }
+            let param = self.name("identifier")?;
+            if let Some(other) = params.iter().find(|other| other.node == param.node) {
+                let report = miette!(
+                    labels = vec![
+                        LabeledSpan::at(other.span.clone(), "previously declared here"),
+                        LabeledSpan::at(param.span.clone(), "declared there"),
+                    ],
+                    "Error: Parameter {} already declared",
+                    param.node
+                );
+                self.report(report);
+                return None;
+            }
+            params.push(param);
+        }
+
+        Some((name, params))
+    }
+
+    /// replace `name arg1, arg2` by the body of the macro
+    fn call(&mut self, program: &Program) -> Option<()> {
+        let start = self.pos;
+        let name = self.expect(&["identifier"], identifier)?;
+
+        let mut args: Vec<Vec<SpannedToken>> = vec![];
+        while !self.end_of_line() {
+            if !args.is_empty() {
+                self.expect(&["`,`", "end of line"], |token| (*token == Comma).then_some(()))?;
+            }
+            let arg_start = self.pos;
+            while !self.end_of_line() && self.peek(0) != Some(&Ok(Comma)) {
+                self.pos += 1;
+            }
+            if arg_start == self.pos {
+                self.error(&["argument"]);
+                return None;
+            }
+            args.push(self.tokens[arg_start..self.pos].to_vec());
+        }
+        let call = self.tokens[start].1.start..self.tokens[self.pos - 1].1.end;
+
+        let definition = &self.macros[&name.node];
+        if args.len() != definition.params.len() {
+            let report = miette!(
+                labels = vec![
+                    LabeledSpan::at(call, format!("called with {} arguments", args.len())),
+                    LabeledSpan::at(definition.name.span.clone(), "defined here"),
+                ],
+                "Error: Macro {} takes {} arguments",
+                name.node,
+                definition.params.len()
+            );
+            self.report(report);
+            return None;
+        }
+
+        self.count += 1;
+        let body = definition.expand(&args, self.count);
+        self.expand(start, body, name.node, call, program)
+    }
+
+    /// `INCLUDE "file"` replaced by the tokens of the file,
+    /// a file which isn't assembled isn't looked for
+    fn include(&mut self, program: &mut Program) -> Option<()> {
+        let start = self.pos;
+        let mut end = start + 1;
+        while !matches!(self.tokens.get(end), None | Some((Ok(Newline), _))) {
+            end += 1;
+        }
+        let directive = self.tokens[start].1.clone();
+        let (name, span) = match &self.tokens[start + 1..end] {
+            [(Ok(Str(name)), span)] => (name.clone(), span.clone()),
+            line => {
+                let wrong = match line {
+                    [] => directive,
+                    [(_, first), ..] => first.start..line[line.len() - 1].1.end,
+                };
+                let report = miette!(
+                    labels = vec![LabeledSpan::at(wrong, "Here")],
+                    help = "Write it between quotes: INCLUDE \"io.asm\"",
+                    "Error: Expected file path"
+                );
+                self.report(report);
+                self.pos = end;
+                return None;
+            }
+        };
+
+        let directive = directive.start..span.end;
+        let tokens: Vec<SpannedToken> = match self.loader.include(&name, span, directive) {
+            Ok(tokens) => tokens.into_iter().filter(|(token, _)| *token != Ok(Comment)).collect(),
+            Err(report) => {
+                self.report(report);
+                self.pos = end;
+                return None;
+            }
+        };
+        for (token, span) in &tokens {
+            if let Err(e) = token {
+                self.report(e.report(span.clone()));
+            }
+        }
+
+        let added = tokens.len() as isize - (end - start) as isize;
+        for (_, end) in &mut self.expanding {
+            *end = end.saturating_add_signed(added);
+        }
+        self.tokens.splice(start..end, tokens);
+        self.pos = start;
+        // the line goes on with the first line of the file
+        self.line(program)
+    }
+
+    /// replace the tokens from `start` to the current position,
+    /// the statements they hold are part of an expansion
+    fn expand(
+        &mut self,
+        start: usize,
+        tokens: Vec<SpannedToken>,
+        name: String,
+        call: Range<usize>,
+        program: &Program,
+    ) -> Option<()> {
+        if self.expanding.len() >= MAX_EXPANSION_DEPTH {
+            let report = miette!(
+                labels = vec![LabeledSpan::at(call, "Here")],
+                help = "A macro can't call itself without end",
+                "Error: Too many nested expansions of {name}"
+            );
+            self.report(report);
+            return None;
+        }
+
+        let added = tokens.len() as isize - (self.pos - start) as isize;
+        for (_, end) in &mut self.expanding {
+            *end = end.saturating_add_signed(added);
+        }
+        let end = (start + tokens.len()).saturating_sub(1);
+        self.tokens.splice(start..self.pos, tokens);
+        self.pos = start;
+
+        let statements = program.statements.len()..program.statements.len();
+        let expansion = Expansion {
+            name,
+            call,
+            statements,
+        };
+        self.expanding.push((expansion, end));
+        Some(())
+    }
+
+    /// `REPT count` or `FOR name = first TO last` up to `ENDR` or `ENDF`,
+    /// replaced by a copy of the body for each iteration
+    fn repetition(&mut self, program: &mut Program) -> Option<()> {
+        let start = self.pos;
+        let (keyword, open, close) = match self.tokens[start].0 {
+            Ok(Directive(Dir::Repeat)) => ("REPT", Dir::Repeat, Dir::EndRepeat),
+            _ => ("FOR", Dir::For, Dir::EndFor),
+        };
+        self.pos += 1;
+        let mut header = self.repetition_header(&open, program);
+        if header.is_some() && !self.end_of_line() {
+            self.error(&["end of line"]);
+            header = None;
+        }
+        while !self.end_of_line() {
+            self.pos += 1;
+        }
+        let call = self.tokens[start].1.start..self.tokens[self.pos - 1].1.end;
+
+        // blocks of the same kind can be nested
+        let body_start = (self.pos + 1).min(self.tokens.len());
+        let mut depth = 0;
+        let mut end = body_start;
+        loop {
+            match self.tokens.get(end) {
+                Some((Ok(Directive(dir)), _)) if *dir == close && depth == 0 => break,
+                Some((Ok(Directive(dir)), _)) if *dir == close => depth -= 1,
+                Some((Ok(Directive(dir)), _)) if *dir == open => depth += 1,
+                Some(_) => (),
+                None => {
+                    let report = miette!(
+                        labels = vec![LabeledSpan::at(call, "block starting here")],
+                        "Error: Missing {}",
+                        describe(&Directive(close))
+                    );
+                    self.report(report);
+                    self.pos = end;
+                    return None;
+                }
+            }
+            end += 1;
+        }
+        let body = self.tokens[body_start..end].to_vec();
+        // ENDR or ENDF
+        self.pos = end + 1;
+        if !self.end_of_line() {
+            self.error(&["end of line"]);
+            return None;
+        }
+
+        let (variable, iterations) = header?;
+        let block = Macro {
+            name: Spanned::new(keyword.to_string(), call.clone()),
+            params: variable.into_iter().collect(),
+            body,
+        };
+        let mut tokens = vec![];
+        for i in iterations {
+            let args = match block.params.is_empty() {
+                true => vec![],
+                false => vec![vec![(Ok(Value(i as u16)), 0..0)]],
+            };
+            self.count += 1;
+            tokens.extend(block.expand(&args, self.count));
+        }
+        self.expand(start, tokens, keyword.to_string(), call, program)
+    }
+
+    /// loop variable and its values
+    fn repetition_header(
+        &mut self,
+        dir: &Dir,
+        program: &mut Program,
+    ) -> Option<(Option<Spanned<String>>, Range<i64>)> {
+        if *dir == Dir::Repeat {
+            let count = self.expression(0)?;
+            let val = self.constant_value(&count, "a repetition count", program)?;
+            if !(0..=MAX_ITERATIONS).contains(&val) {
+                let report = out_of_range(count.span, val, MAX_ITERATIONS as u16);
+                self.report(report);
+                return None;
+            }
+            return Some((None, 0..val));
+        }
+
+        let name = self.name("identifier")?;
+        self.expect(&["`=`"], |token| (*token == Assignement).then_some(()))?;
+        let first = self.expression(0)?;
+        self.expect(&["TO"], |token| (*token == Directive(Dir::To)).then_some(()))?;
+        let last = self.expression(0)?;
+
+        let mut bounds = [0; 2];
+        for (bound, expr) in bounds.iter_mut().zip([first, last]) {
+            let val = self.constant_value(&expr, "a loop bound", program)?;
+            if !(0..=u16::MAX as i64).contains(&val) {
+                self.report(out_of_range(expr.span, val, u16::MAX));
+                return None;
+            }
+            *bound = val;
+        }
+        let [first, last] = bounds;
+        if last - first >= MAX_ITERATIONS {
+            let iterations = format!("{} iterations", last - first + 1);
+            let report = miette!(
+                labels = vec![LabeledSpan::at(name.span, iterations)],
+                help = format!("A loop can't run more than {MAX_ITERATIONS} times"),
+                "Error: Too many iterations"
+            );
+            self.report(report);
+            return None;
+        }
+        Some((Some(name), first..last + 1))
+    }
+
+    /// `SET name expr`, the variable is replaced by its value in the
+    /// following statements until it is set again
+    fn set(&mut self, program: &mut Program) -> Option<()> {
+        self.pos += 1;
+        let name = self.name("identifier")?;
+        let value = self.expression(0)?;
+        if !self.end_of_line() {
+            self.error(&["end of line"]);
+            return None;
+        }
+
+        if self.defined.contains(&name.node) {
+            let report = miette!(
+                labels = vec![LabeledSpan::at(name.span, "Here")],
+                help = "Only the variables created by SET can change",
+                "Error: Can't SET the constant {}",
+                name.node
+            );
+            self.report(report);
+            return None;
+        }
+        let val = self.constant_value(&value, "SET", program)?;
+        if !(0..=u16::MAX as i64).contains(&val) {
+            self.report(out_of_range(value.span, val, u16::MAX));
+            return None;
+        }
+
+        let symbol = ColType {
+            val: val as u16,
+            span: name.span,
+            visited: false,
+        };
+        if !self.variables.contains(&name.node) {
+            self.variables.push(name.node.clone());
+        }
+        self.symbols.insert(name.node, symbol);
+        Some(())
+    }
+
+    /// replace the variables by their current value
+    fn bind(&self, statement: &mut Statement) {
+        for value in expressions(statement) {
+            value.node.bind(&|id| {
+                self.variables
+                    .iter()
+                    .any(|variable| variable == id)
+                    .then(|| self.symbols[id].val)
+            });
+        }
+    }
+
+    /// `.fill count, value` or `.zero count`, the count is known while parsing
+    /// so that the addresses of the following labels are
+    fn fill(&mut self, program: &mut Program) -> Option<Statement> {
+        let (token, directive) = self.tokens[self.pos].clone();
+        self.pos += 1;
+        let count = self.expression(0)?;
+        let value = match token {
+            Ok(Directive(Dir::Zero)) => Spanned::new(Expr::Value(0), directive),
+            _ => {
+                self.expect(&["`,`"], |token| (*token == Comma).then_some(()))?;
+                self.expression(0)?
+            }
+        };
+
+        let val = self.constant_value(&count, "a fill count", program)?;
+        if !(0..=u16::MAX as i64).contains(&val) {
+            self.report(out_of_range(count.span, val, u16::MAX));
+            return None;
+        }
+        Some(Statement::Word(vec![value; val as usize]))
+    }
+
+    /// `ORG address`, the address is known while parsing like a fill count
+    fn org(&mut self, program: &mut Program) -> Option<Statement> {
+        self.pos += 1;
+        let address = self.expression(0)?;
+        let val = self.constant_value(&address, "ORG", program)?;
+        if !(0..=u16::MAX as i64).contains(&val) {
+            self.report(out_of_range(address.span, val, u16::MAX));
+            return None;
+        }
+        Some(Statement::Org(val as u16))
+    }
+
+    /// `.string "text"` or `.stringz "text"` ending with a 0, one character per word
+    /// or two with `, packed` where the first one takes the high byte
+    fn string(&mut self) -> Option<Statement> {
+        let zero = self.peek(0) == Some(&Ok(Directive(Dir::StringZero)));
+        self.pos += 1;
+        let text = self.expect(&["string"], |token| match token {
+            Str(text) => Some(text.clone()),
+            _ => None,
+        })?;
+        let packed = self.peek(0) == Some(&Ok(Comma));
+        if packed {
+            self.pos += 1;
+            self.expect(&["packed"], |token| {
+                (*token == Identifier("packed".to_string())).then_some(())
+            })?;
+        }
+
+        let mut bytes = vec![];
+        for c in text.node.chars() {
+            if c as u32 > 0xff {
+                self.report(LexError::CharacterOutOfRange.report(text.span.clone()));
+                return None;
+            }
+            bytes.push(c as u16);
+        }
+        if zero {
+            bytes.push(0);
+        }
+        let words: Vec<u16> = match packed {
+            true => bytes
+                .chunks(2)
+                .map(|pair| pair[0] << 8 | pair.get(1).copied().unwrap_or(0))
+                .collect(),
+            false => bytes,
+        };
+        let values = words
+            .into_iter()
+            .map(|word| Spanned::new(Expr::Value(word), text.span.clone()))
+            .collect();
+        Some(Statement::Word(values))
+    }
+
+    fn statement(&mut self) -> Option<Statement> {
+        if let (Some((Ok(token), span)), Some(Ok(Directive(Dir::Label)))) =
+            (self.tokens.get(self.pos), self.peek(1))
+        {
+            if let Some(word) = reserved(token) {
+                let report = reserved_report(&word, span.clone());
+                self.report(report);
+                return None;
+            }
+        }
+
+        match self.peek(0) {
+            Some(Ok(Register(_))) => {
+                let dest = self.expect(&["register"], register)?;
+                match self.peek(0) {
+                    Some(Ok(Assignement)) => {
+                        self.pos += 1;
+                        self.assignment(dest)
+                    }
+                    Some(Ok(Condition(_))) => {
+                        let cond = self.expect(&["condition"], |token| match token {
+                            Condition(cond) => Some(*cond),
+                            _ => None,
+                        })?;
+                        Some(Statement::Condition { reg: dest, cond })
+                    }
+                    _ => {
+                        self.error(&["`=`", "condition"]);
+                        None
+                    }
+                }
+            }
+            Some(Ok(Condition(Cond::Jump))) => {
+                self.pos += 1;
+                Some(Statement::Jump)
+            }
+            Some(Ok(Value(label))) if self.peek(1) == Some(&Ok(Directive(Dir::Label))) => {
+                let label = *label;
+                let span = self.tokens[self.pos].1.clone();
+                self.pos += 2;
+                let count = self.numeric.entry(label).or_insert(0);
+                let name = format!("{label}#{count}");
+                *count += 1;
+                Some(Statement::Label(Spanned::new(name, span)))
+            }
+            Some(Ok(Identifier(_))) => {
+                let id = self.expect(&["identifier"], identifier)?;
+                self.expect(&["`:`"], |token| {
+                    matches!(token, Directive(Dir::Label)).then_some(())
+                })?;
+                Some(Statement::Label(id))
+            }
+            Some(Ok(Directive(Dir::Define))) => {
+                self.pos += 1;
+                let id = self.name("identifier")?;
+                let value = self.expression(0)?;
+                Some(Statement::Define { id, value })
+            }
+            Some(Ok(Directive(Dir::Word))) => {
+                self.pos += 1;
+                let mut values = vec![self.expression(0)?];
+                while self.peek(0) == Some(&Ok(Comma)) {
+                    self.pos += 1;
+                    values.push(self.expression(0)?);
+                }
+                Some(Statement::Word(values))
+            }
+            Some(Ok(Directive(Dir::String | Dir::StringZero))) => self.string(),
+            Some(Ok(Directive(Dir::Section))) => {
+                self.pos += 1;
+                let name = self.name("section name")?;
+                Some(Statement::Section(name.node))
+            }
+            Some(Ok(Directive(Dir::Text))) => {
+                self.pos += 1;
+                Some(Statement::Section("text".to_string()))
+            }
+            Some(Ok(Directive(Dir::Data))) => {
+                self.pos += 1;
+                Some(Statement::Section("data".to_string()))
+            }
+            Some(Ok(Directive(Dir::Entry))) => {
+                self.pos += 1;
+                let label = self.name("label")?;
+                Some(Statement::Entry(label))
+            }
+            Some(Ok(Directive(Dir::Arch(name)))) => {
+                let name = name.clone();
+                // an unknown one is reported once the program is parsed
+                if let Some(arch) = self.targets.get(&name) {
+                    self.arch = arch;
+                }
+                let arch = Statement::Arch(name);
+                self.pos += 1;
+                Some(arch)
+            }
+            _ => {
+                self.error(&STATEMENT_START);
+                None
+            }
+        }
+    }
+
+    /// right hand side of `dest = ...`
+    fn assignment(&mut self, dest: Spanned<Reg>) -> Option<Statement> {
+        match self.peek(0) {
+            Some(Ok(Operation(Op::Not))) if matches!(self.peek(1), Some(Ok(Register(_)))) => {
+                self.pos += 1;
+                let src = self.expect(&["register"], register)?;
+                Some(Statement::Not { dest, src })
+            }
+            Some(Ok(Register(_))) => {
+                let a = self.expect(&["register"], register)?;
+                if !matches!(self.peek(0), Some(Ok(Operation(_)))) {
+                    return Some(Statement::Transfer { dest, src: a });
+                }
+                self.binary(dest, a)
+            }
+            // `1 - D` reads O, `1 - 1` is still a loaded expression
+            Some(Ok(Value(0 | 1)))
+                if matches!(
+                    (self.peek(1), self.peek(2)),
+                    (Some(Ok(Operation(_))), Some(Ok(Register(_))))
+                ) =>
+            {
+                let a = self.expect(&["register"], alu_source)?;
+                self.binary(dest, a)
+            }
+            _ => {
+                let value = self.expression(0)?;
+                Some(Statement::Load {
+                    dest,
+                    value,
+                    constant: None,
+                })
+            }
+        }
+    }
+
+    /// `op b` of `dest = a op b`
+    fn binary(&mut self, dest: Spanned<Reg>, a: Spanned<Reg>) -> Option<Statement> {
+        let op = self.expect(&["operation"], |token| match token {
+            Operation(op) => Some(*op),
+            _ => None,
+        })?;
+        let b = self.expect(&["register", "`0`", "`1`"], alu_source)?;
+        Some(Statement::Binary { dest, a, op, b })
+    }
+
+    /// constant expression made of operators with at least `min_precedence`
+    fn expression(&mut self, min_precedence: u8) -> Option<Spanned<Expr>> {
+        let mut lhs = self.operand()?;
+
+        while let Some(Ok(token)) = self.peek(0) {
+            let Some(op) = binary_op(token).filter(|op| op.precedence() >= min_precedence) else {
+                break;
+            };
+            self.pos += 1;
+            // operators of the same precedence are applied from left to right
+            let rhs = self.expression(op.precedence() + 1)?;
+            let span = lhs.span.start..rhs.span.end;
+            lhs = Spanned::new(
+                Expr::Binary {
+                    op,
+                    lhs: Box::new(lhs),
+                    rhs: Box::new(rhs),
+                },
+                span,
+            );
+        }
+
+        Some(lhs)
+    }
+
+    fn operand(&mut self) -> Option<Spanned<Expr>> {
+        let start = self.tokens.get(self.pos).map_or(0, |(_, span)| span.start);
+
+        let unary = match self.peek(0) {
+            Some(Ok(Operation(Op::Sub))) => Some(UnaryOp::Neg),
+            Some(Ok(Operation(Op::Not))) => Some(UnaryOp::Not),
+            _ => None,
+        };
+        if let Some(op) = unary {
+            self.pos += 1;
+            let expr = self.operand()?;
+            let span = start..expr.span.end;
+            return Some(Spanned::new(
+                Expr::Unary {
+                    op,
+                    expr: Box::new(expr),
+                },
+                span,
+            ));
+        }
+
+        if let Some(Ok(OpenParen)) = self.peek(0) {
+            self.pos += 1;
+            let expr = self.expression(0)?;
+            let close = self.expect(&["`)`"], |token| (*token == CloseParen).then_some(()))?;
+            return Some(Spanned::new(expr.node, start..close.span.end));
+        }
+
+        if let Some((Ok(Reference(reference)), span)) = self.tokens.get(self.pos).cloned() {
+            self.pos += 1;
+            let defined = self.numeric.get(&reference.label).copied().unwrap_or(0);
+            if reference.forward {
+                self.forward.push((reference, defined, span.clone()));
+                let name = format!("{}#{defined}", reference.label);
+                return Some(Spanned::new(Expr::Id(name), span));
+            }
+            if defined == 0 {
+                let report = miette!(
+                    labels = vec![LabeledSpan::at(span, "Here")],
+                    "Error: No label {}: before this reference",
+                    reference.label
+                );
+                self.report(report);
+                return None;
+            }
+            let name = format!("{}#{}", reference.label, defined - 1);
+            return Some(Spanned::new(Expr::Id(name), span));
+        }
+
+        self.expect(&EXPRESSION_START, |token| match token {
+            Value(val) => Some(Expr::Value(*val)),
+            Identifier(id) => Some(Expr::Id(id.clone())),
+            Here => Some(Expr::Here),
+            _ => None,
+        })
+    }
 }
 
-pub struct ParserReport {
-    pub bit_stream: String,
-    pub report: Vec<Error>,
+/// build the syntax tree of a program, comments are dropped and
+/// the files included by the lines assembled are lexed by `loader`
+pub fn parse_program(
+    tokens: &[(Result<Token, LexError>, Range<usize>)],
+    loader: &mut Loader,
+    targets: &Targets,
+    defines: &[(String, u16)],
+) -> (Program, Vec<Error>) {
+    let symbols = defines
+        .iter()
+        .map(|(name, val)| (name.clone(), command_line_symbol(*val)))
+        .collect();
+    let mut grammar = Grammar {
+        tokens: tokens
+            .iter()
+            .filter(|(token, _)| *token != Ok(Comment))
+            .cloned()
+            .collect(),
+        pos: 0,
+        errors: vec![],
+        macros: HashMap::new(),
+        expanding: vec![],
+        count: 0,
+        symbols,
+        defined: defines.iter().map(|(name, _)| name.clone()).collect(),
+        blocks: vec![],
+        variables: vec![],
+        scope: String::new(),
+        numeric: HashMap::new(),
+        forward: vec![],
+        loader: std::mem::take(loader),
+        targets,
+        arch: targets.active(),
+    };
+    let (program, errors) = grammar.program();
+    *loader = grammar.loader;
+    (program, errors)
+}
+
+/// expressions of a statement
+fn expressions(statement: &mut Statement) -> &mut [Spanned<Expr>] {
+    match statement {
+        Statement::Load { value, .. } | Statement::Define { value, .. } => {
+            std::slice::from_mut(value)
+        }
+        Statement::Word(values) => values.as_mut_slice(),
+        _ => &mut [],
+    }
+}
+
+/// constant given with `-D`, it has no span in the source
+/// and doesn't need to be used
+fn command_line_symbol(val: u16) -> ColType {
+    ColType {
+        val,
+        span: 0..0,
+        visited: true,
+    }
+}
 
-    // this field is only used for test purpose
-    #[allow(dead_code)]
-    id_collect: HashMap<String, ColType>,
+/// report an incompatible destination and source, if any
+fn check_conflict(
+    arch: &dyn Architecture,
+    dest: &Spanned<Reg>,
+    src: Reg,
+    src_span: Range<usize>,
+    errors: &mut Vec<Error>,
+) {
+    let regc = dest.node;
+    if arch.conflicts(regc, src) {
+        let report = miette!(
+            labels = vec![
+                LabeledSpan::at(dest.span.clone(), "This"),
+                LabeledSpan::at(src_span, "and this are incompatible"),
+            ],
+            "Error Can't change {regc} value when reading {src}"
+        );
+        errors.push(report);
+    }
+}
+
+/// report a data load into another register than the load register
+fn check_load(arch: &dyn Architecture, dest: &Spanned<Reg>, value: &str, errors: &mut Vec<Error>) {
+    let load = arch.load_register();
+    let regc = dest.node;
+    if regc != load {
+        let report = miette!(
+            labels = vec![LabeledSpan::at(dest.span.clone(), format!("This should be {load}"))],
+            help = format!("Consider using this: \n{load} = {value}\n{regc} = {load}"),
+            "Error Can't push direct value into other register than {load}"
+        );
+        errors.push(report);
+    }
 }
 
-/// generate a bit stream from a Vec of Spanned Token
+/// generate the instructions of a program from a Vec of Spanned Token
 pub fn parse(
-    tokens: &mut Vec<(Result<Token, ()>, Range<usize>)>,
-    colorize: bool,
+    tokens: &[(Result<Token, LexError>, Range<usize>)],
+    loader: &mut Loader,
+    targets: &mut Targets,
+    defines: &[(String, u16)],
+    layout: &SectionLayout,
     debug: bool,
-    sep: &str,
 ) -> ParserReport {
-    colored::control::set_override(colorize);
-    let mut bit_stream_with_id: Vec<String> = vec![];
-
     let mut errors: Vec<Error> = vec![];
 
-    // Hashmap for the identifiers
-    let mut id_collect: HashMap<String, ColType> = HashMap::new();
-    let mut adr = 0;
+    for (token, span) in tokens {
+        if let Err(e) = token {
+            errors.push(e.report(span.clone()));
+        }
+    }
+
+    let (mut program, syntax_errors) = parse_program(tokens, loader, targets, defines);
+    errors.extend(syntax_errors);
+
+    if debug {
+        for statement in &program.statements {
+            println!("{:?}", statement);
+        }
+    }
 
-    let mut i = 0;
-    let n = tokens.len();
+    // `.arch` is applied when its block is assembled
+    for Spanned { node, span } in &program.statements {
+        if let Statement::Arch(name) = node {
+            if !targets.select(name) {
+                let valid = targets.names().iter().map(|name| name.to_string()).collect();
+                errors.push(LexError::UnknownArch { valid }.report(span.clone()));
+            }
+        }
+    }
+    let arch = targets.active();
+
+    size_loads(&mut program, arch, layout, defines, &mut errors);
+    let entry = entry(&program, &mut errors);
+    let (stub, reserved) = stub(&program, arch, layout, entry);
+    let addresses = locate(&program, arch, layout, reserved, &mut errors);
+    let mut id_collect = collect_symbols(&program, &addresses, arch, defines, &mut errors);
+    if let Some(symbol) = entry.and_then(|(name, _)| id_collect.get_mut(&name.node)) {
+        symbol.visited = true;
+    }
 
-    // fill the token vec with 5 comments
-    for _ in 0..5 {
-        tokens.push((Ok(Token::Comment), 0..0));
+    if debug {
+        println!("{:.?}", id_collect);
     }
 
-    while i < n {
-        let tokens_window = &tokens[i..(i + 5)];
+    let (mut words, fixups) = emit(&program, &addresses, arch, &mut errors);
+    if let Some((name, label)) = stub {
+        words.extend(reset_stub(addresses[label], name, arch));
+    }
+    let notes = resolve(&mut words, &fixups, &program, &mut id_collect, arch, &mut errors);
+    let (instructions, spans) = place(&words, arch, layout.fill, &mut errors);
+    let per_word = arch.address_unit().per_word() as usize;
+    let notes = notes.into_iter().map(|(adr, note)| (adr / per_word, note)).collect();
+
+    for id in &program.referenced {
+        if let Some(symbol) = id_collect.get_mut(id) {
+            symbol.visited = true;
+        }
+    }
 
-        if debug {
-            println!("{i}, {:?}", tokens_window);
+    // a label of a macro is reported once for all its expansions
+    let mut warned: Vec<Range<usize>> = vec![];
+    for (key, context) in id_collect.clone() {
+        // numeric labels are meant to be thrown away
+        if key.starts_with(|c: char| c.is_ascii_digit()) {
+            continue;
         }
+        if let ColType {
+            val: _,
+            span,
+            visited: false,
+        } = context
+        {
+            if warned.contains(&span) {
+                continue;
+            }
+            warned.push(span.clone());
+            let report = miette!(
+                severity = Severity::Warning,
+                labels = vec![LabeledSpan::at(span, "Here"),],
+                "Error: {} declared but never used",
+                source_name(&key)
+            );
+            errors.push(report);
+        }
+    }
+
+    ParserReport {
+        instructions,
+        spans,
+        report: errors,
+        id_collect,
+        notes,
+    }
+}
 
-        let inst_word = match tokens_window {
-            // A <- D & *A
-            [(Ok(Register(regc)), spanc), (Ok(Assignement), _), (Ok(Register(rega)), spana), (Ok(Operation(op)), spanop), (Ok(Register(regb)), spanb)] =>
+/// the entry label when the reset stub jumps to it, with the addresses
+/// the stub takes: it is needed when the entry isn't at the first word
+fn stub<'p>(
+    program: &Program,
+    arch: &dyn Architecture,
+    layout: &SectionLayout,
+    entry: Option<(&'p Spanned<String>, usize)>,
+) -> (Option<(&'p Spanned<String>, usize)>, usize) {
+    let stub = entry.filter(|(_, label)| {
+        locate(program, arch, layout, 0, &mut vec![])[*label] != 0
+    });
+    let reserved = match stub {
+        Some((name, _)) => reset_stub(0, name, arch).len() * arch.address_unit().per_word() as usize,
+        None => 0,
+    };
+    (stub, reserved)
+}
+
+/// value of the loads which aren't known while parsing, such as a load of
+/// a DEFINE given below it, the program is laid out again until the size
+/// of these loads doesn't change
+fn size_loads(
+    program: &mut Program,
+    arch: &dyn Architecture,
+    layout: &SectionLayout,
+    defines: &[(String, u16)],
+    errors: &mut Vec<Error>,
+) {
+    let open: Vec<usize> = (program.statements.iter().enumerate())
+        .filter(|(_, statement)| match &statement.node {
+            Statement::Load {
+                value,
+                constant: None,
+                ..
+            } => !value.node.uses_here(),
+            _ => false,
+        })
+        .map(|(index, _)| index)
+        .collect();
+    if open.is_empty() {
+        return;
+    }
+
+    let mut resized = vec![];
+    for _ in 0..MAX_LAYOUTS {
+        let entry = entry(program, &mut vec![]).map(|(name, label)| (name.clone(), label));
+        let (_, reserved) = stub(program, arch, layout, entry.as_ref().map(|(name, label)| (name, *label)));
+        let addresses = locate(program, arch, layout, reserved, &mut vec![]);
+        let mut symbols = collect_symbols(program, &addresses, arch, defines, &mut vec![]);
+
+        resized.clear();
+        for &index in &open {
+            let statement = &mut program.statements[index];
+            let size = words(&statement.node, arch);
+            if let Statement::Load {
+                value, constant, ..
+            } = &mut statement.node
             {
-                if *regc == Reg::A && (*rega == Reg::AStar || *regb == Reg::AStar) {
-                    let report = miette!(
-                        labels = vec![
-                            LabeledSpan::at(spanc.clone(), "This"),
-                            LabeledSpan::at(
-                                (spana.clone()).start..(spanb.clone().end),
-                                "and this are incompatible"
-                            ),
-                        ],
-                        "Error Can't change A value when reading *A"
-                    );
-                    errors.push(report);
-                }
-                if *regc == Reg::V && (*rega == Reg::VStar || *regb == Reg::VStar) {
-                    let report = miette!(
-                        labels = vec![
-                            LabeledSpan::at(spanc.clone(), "This"),
-                            LabeledSpan::at(
-                                (spana.clone()).start..(spanb.clone().end),
-                                "and this are incompatible"
-                            ),
-                        ],
-                        "Error Can't change V value when reading *V"
-                    );
-                    errors.push(report);
-                }
-                if *op == Op::Not {
-                    let report = miette!(
-                        labels = vec![
-                            LabeledSpan::at(spana.clone(), "Excessive operand"),
-                            LabeledSpan::at(spanop.clone(), "with operation ~"),
-                        ],
-                        help = format!("Try removing {:?}", *rega),
-                        "Error Too many operand for ~"
-                    );
-                    errors.push(report);
+                *constant = eval(value, 0, &mut symbols).ok().and_then(word);
+            }
+            if words(&statement.node, arch) != size {
+                resized.push(statement.span.clone());
+            }
+        }
+        if resized.is_empty() {
+            return;
+        }
+    }
+
+    // the loads keep moving the labels they read
+    let report = miette!(
+        labels = resized
+            .into_iter()
+            .map(|span| LabeledSpan::at(span, "resized"))
+            .collect::<Vec<_>>(),
+        help = "Load the value with explicit instructions",
+        "Error: The size of the loads doesn't settle"
+    );
+    errors.push(report);
+}
+
+/// number of words a statement is assembled into
+fn words(statement: &Statement, arch: &dyn Architecture) -> usize {
+    match statement {
+        Statement::Load {
+            constant: Some(val),
+            ..
+        } if *val > arch.max_load_value() && from_sources(statement, arch).is_none() => 2,
+        Statement::Label(_)
+        | Statement::Define { .. }
+        | Statement::Arch(_)
+        | Statement::Org(_)
+        | Statement::Section(_)
+        | Statement::Entry(_) => 0,
+        Statement::Word(values) => values.len(),
+        _ => 1,
+    }
+}
+
+/// DEFINE whose value is computed once the constants it reads are known
+struct Pending<'p> {
+    id: &'p Spanned<String>,
+    value: &'p Spanned<Expr>,
+    /// address following the DEFINE
+    here: usize,
+    calls: Vec<&'p Expansion>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Visit {
+    New,
+    InProgress,
+    Done,
+    /// part of a cycle or reading one
+    Failed,
+}
+
+/// label the program starts from, given by `.entry` or `main`,
+/// with the index of its statement
+fn entry<'p>(
+    program: &'p Program,
+    errors: &mut Vec<Error>,
+) -> Option<(&'p Spanned<String>, usize)> {
+    let mut given: Option<&Spanned<String>> = None;
+    for statement in &program.statements {
+        let Statement::Entry(name) = &statement.node else {
+            continue;
+        };
+        match given {
+            Some(first) => {
+                let report = miette!(
+                    labels = vec![
+                        LabeledSpan::at(first.span.clone(), "first given here"),
+                        LabeledSpan::at(name.span.clone(), "given again there"),
+                    ],
+                    "Error: Entry point already given"
+                );
+                errors.push(report);
+            }
+            None => given = Some(name),
+        }
+    }
+
+    let name = given.map_or("main", |name| name.node.as_str());
+    let label = program
+        .statements
+        .iter()
+        .position(|statement| matches!(&statement.node, Statement::Label(id) if id.node == name));
+    match (label, given) {
+        (Some(index), Some(given)) => Some((given, index)),
+        (Some(index), None) => match &program.statements[index].node {
+            Statement::Label(id) => Some((id, index)),
+            _ => None,
+        },
+        (None, Some(given)) => {
+            let report = miette!(
+                severity = Severity::Warning,
+                labels = vec![LabeledSpan::at(given.span.clone(), "Here")],
+                help = "The program starts at address 0",
+                "Error: No label {} to start from",
+                given.node
+            );
+            errors.push(report);
+            None
+        }
+        (None, None) => None,
+    }
+}
+
+/// `A = entry` and `JMP` at address 0, an entry out of range is
+/// reported with its label
+fn reset_stub(entry: usize, name: &Spanned<String>, arch: &dyn Architecture) -> Vec<Placed> {
+    let per_word = arch.address_unit().per_word() as usize;
+    [Instruction::Load(entry as u16), Instruction::Jump]
+        .into_iter()
+        .enumerate()
+        .map(|(i, inst)| Placed {
+            inst,
+            span: name.span.clone(),
+            adr: i * per_word,
+        })
+        .collect()
+}
+
+/// address of the first word of each statement, a section goes on
+/// from its last statement when it is entered again, `.text` starts
+/// after the `reserved` addresses when the layout doesn't place it
+fn locate(
+    program: &Program,
+    arch: &dyn Architecture,
+    layout: &SectionLayout,
+    reserved: usize,
+    errors: &mut Vec<Error>,
+) -> Vec<usize> {
+    let per_word = arch.address_unit().per_word() as usize;
+    // wider than an address so that overflowing labels can be reported
+    let text = layout.base("text").map_or(reserved, |base| base as usize);
+    let mut sections: Vec<(&str, usize)> = vec![("text", text)];
+    let mut current = 0;
+    let mut addresses = vec![];
+
+    for Spanned { node: statement, span } in &program.statements {
+        let moved = match statement {
+            Statement::Section(name) => {
+                match sections.iter().position(|(known, _)| known == name) {
+                    Some(index) => current = index,
+                    None => {
+                        // after the words placed so far
+                        let end = sections.iter().map(|(_, adr)| *adr).max().unwrap_or(0);
+                        let base = layout.base(name).map_or(end, |base| base as usize);
+                        sections.push((name, base));
+                        current = sections.len() - 1;
+                    }
                 }
-                i += 5;
-                adr += 16;
-                inst_mode_format(OpOrCond::Operation(*op), *rega, *regb, *regc)
+                true
             }
-            // A <- mask, tested
-            [(Ok(Register(regc)), span), (Ok(Assignement), _), (Ok(Identifier(id)), spanid), _, _] =>
-            {
-                if *regc != Reg::A {
+            Statement::Org(adr) => {
+                sections[current].1 = *adr as usize;
+                true
+            }
+            _ => false,
+        };
+
+        let adr = sections[current].1;
+        if moved && !adr.is_multiple_of(per_word) {
+            let report = miette!(
+                labels = vec![LabeledSpan::at(span.clone(), format!("at address {adr}"))],
+                help = format!("An address must be a multiple of {per_word}"),
+                "Error: Words can't start at address {adr}"
+            );
+            errors.push(report);
+            sections[current].1 += per_word - adr % per_word;
+        }
+        let adr = sections[current].1;
+        let end = adr + words(statement, arch) * per_word;
+        // only the statement crossing the end of the address space
+        if adr <= ADDRESS_SPACE && end > ADDRESS_SPACE {
+            let report = miette!(
+                labels = vec![LabeledSpan::at(span.clone(), format!("ends at address {}", end - 1))],
+                help = format!("Addresses go up to {}", ADDRESS_SPACE - 1),
+                "Error: Address out of range"
+            );
+            errors.push(report);
+        }
+        addresses.push(adr);
+        sections[current].1 = end;
+    }
+
+    addresses
+}
+
+/// first pass, collect the labels and the defined constants
+fn collect_symbols(
+    program: &Program,
+    addresses: &[usize],
+    arch: &dyn Architecture,
+    defines: &[(String, u16)],
+    errors: &mut Vec<Error>,
+) -> HashMap<String, ColType> {
+    let mut id_collect: HashMap<String, ColType> = defines
+        .iter()
+        .map(|(name, val)| (name.clone(), command_line_symbol(*val)))
+        .collect();
+    let mut pending: Vec<Pending> = vec![];
+    // whether a word comes before the statement
+    let mut placed = false;
+
+    for (index, Spanned { node: statement, span }) in program.statements.iter().enumerate() {
+        let from = errors.len();
+        let adr = addresses[index];
+        match statement {
+            // a label past a data mode word is loaded by two instructions
+            Statement::Label(id) => {
+                if adr >= ADDRESS_SPACE {
                     let report = miette!(
-                        labels = vec![LabeledSpan::at(span.clone(), "This should be A")],
-                        help = format!("Consider using this: \nA = {id}\n{:?} = A", *regc),
-                        "Error Can't push direct value into other register than A"
+                        labels = vec![LabeledSpan::at(id.span.clone(), format!("at address {adr}"))],
+                        help = format!("Addresses go up to {}", ADDRESS_SPACE - 1),
+                        "Error: Label {} is out of range",
+                        id.node
                     );
                     errors.push(report);
                 }
+                declare(&mut id_collect, id, adr as u16, errors);
+            }
+            // the value is set once every symbol is declared
+            Statement::Define { id, value } if declare(&mut id_collect, id, 0, errors) => {
+                let calls = program.calls(index);
+                pending.push(Pending { id, value, here: adr, calls });
+            }
+            Statement::Arch(name) if placed => {
+                let report = miette!(
+                    labels = vec![LabeledSpan::at(span.clone(), "Here")],
+                    help = "Move it at the top of the file",
+                    "Error: .arch {name} must come before any instruction"
+                );
+                errors.push(report);
+            }
+            _ => (),
+        }
+        placed |= words(statement, arch) > 0;
+        trace(errors, from, &program.calls(index));
+    }
+
+    let indexes: HashMap<&str, usize> = pending
+        .iter()
+        .enumerate()
+        .map(|(index, define)| (define.id.node.as_str(), index))
+        .collect();
+    let mut defines = Defines {
+        pending: &pending,
+        indexes: &indexes,
+        visits: vec![Visit::New; pending.len()],
+        stack: vec![],
+    };
+    for index in 0..pending.len() {
+        defines.define(index, &mut id_collect, errors);
+    }
+
+    id_collect
+}
+
+/// Depth first evaluation of the DEFINEs, each one after those it reads
+struct Defines<'d, 'p> {
+    pending: &'d [Pending<'p>],
+    indexes: &'d HashMap<&'p str, usize>,
+    visits: Vec<Visit>,
+    /// DEFINEs being evaluated, each one reads the next
+    stack: Vec<usize>,
+}
 
-                i += 3;
-                adr += 16;
-                format!("{}#{}#{}#", id, spanid.start, spanid.end)
+impl Defines<'_, '_> {
+    /// compute the value of a DEFINE, returns false if it can't be
+    /// because of a cycle
+    fn define(
+        &mut self,
+        index: usize,
+        id_collect: &mut HashMap<String, ColType>,
+        errors: &mut Vec<Error>,
+    ) -> bool {
+        match self.visits[index] {
+            Visit::Done => return true,
+            Visit::Failed => return false,
+            Visit::InProgress => {
+                let start = self.stack.iter().position(|i| *i == index).unwrap_or(0);
+                errors.push(self.cycle_report(&self.stack[start..]));
+                return false;
             }
-            // A <- 0x7fff, tested
-            [(Ok(Register(regc)), span), (Ok(Assignement), _), (Ok(Value(val)), _), _, _] => {
-                if *regc != Reg::A {
-                    let report = miette!(
-                        labels = vec![LabeledSpan::at(span.clone(), "This should be A")],
-                        help = format!("Consider using this: \nA = {val}\n{:?} = A", *regc),
-                        "Error Can't push direct value into other register than A"
-                    );
-                    errors.push(report);
+            Visit::New => (),
+        }
+
+        let Pending { id, value, here, ref calls } = self.pending[index];
+        let ids = value.node.identifiers();
+
+        self.visits[index] = Visit::InProgress;
+        self.stack.push(index);
+        let mut valid = true;
+        for dependency in ids.iter().filter_map(|name| self.indexes.get(name)) {
+            valid &= self.define(*dependency, id_collect, errors);
+        }
+        self.stack.pop();
+
+        if !valid {
+            self.visits[index] = Visit::Failed;
+            // the cycle is already reported
+            for name in ids {
+                if let Some(symbol) = id_collect.get_mut(name) {
+                    symbol.visited = true;
                 }
+            }
+            return false;
+        }
 
-                i += 3;
-                adr += 16;
-                data_mode_format(*val)
+        let from = errors.len();
+        let val = match eval(value, here, id_collect) {
+            Ok(val) if (0..=u16::MAX as i64).contains(&val) => val as u16,
+            Ok(val) => {
+                errors.push(out_of_range(value.span.clone(), val, u16::MAX));
+                0
             }
-            // A <- D, tested
-            [(Ok(Register(regc)), spanc), (Ok(Assignement), _), (Ok(Register(rega)), spana), _, _] =>
-            {
-                if *regc == Reg::A && *rega == Reg::AStar {
-                    let report = miette!(
-                        labels = vec![
-                            LabeledSpan::at(spanc.clone(), "This"),
-                            LabeledSpan::at(spana.clone(), "and this are incompatible"),
-                        ],
-                        "Error Can't change A value when reading *A"
-                    );
-                    errors.push(report);
+            Err(EvalError::Unknown(uses)) => {
+                errors.extend(unknown_reports(uses));
+                0
+            }
+            Err(EvalError::Invalid(e)) => {
+                errors.push(e);
+                0
+            }
+        };
+        trace(errors, from, calls);
+        if let Some(symbol) = id_collect.get_mut(&id.node) {
+            symbol.val = val;
+        }
+        self.visits[index] = Visit::Done;
+        true
+    }
+
+    /// report listing every DEFINE of a cycle
+    fn cycle_report(&self, cycle: &[usize]) -> Error {
+        let names: Vec<&str> = cycle
+            .iter()
+            .chain(cycle.first())
+            .map(|i| self.pending[*i].id.node.as_str())
+            .collect();
+        let labels: Vec<LabeledSpan> = cycle
+            .iter()
+            .zip(&names[1..])
+            .map(|(i, next)| LabeledSpan::at(self.pending[*i].id.span.clone(), format!("reads {next}")))
+            .collect();
+
+        miette!(
+            labels = labels,
+            help = "A constant can't depend on its own value",
+            "Error: Circular definition {}",
+            names.join(" -> ")
+        )
+    }
+}
+
+/// second pass, generate the instructions, the loads of expressions are
+/// left to 0 and recorded as fixups
+fn emit(
+    program: &Program,
+    addresses: &[usize],
+    arch: &dyn Architecture,
+    errors: &mut Vec<Error>,
+) -> (Vec<Placed>, Vec<Fixup>) {
+    let per_word = arch.address_unit().per_word() as usize;
+    let mut words = vec![];
+    let mut fixups = vec![];
+
+    for (index, Spanned { node: statement, span }) in program.statements.iter().enumerate() {
+        let from = errors.len();
+        check_names(statement, span, arch, errors);
+        let inst = match statement {
+            Statement::Binary { dest, a, op, b } => {
+                for src in [a.node, b.node] {
+                    check_conflict(arch, dest, src, a.span.start..b.span.end, errors);
                 }
-                if *regc == Reg::V && *rega == Reg::VStar {
+                // Z and O may be missing when `0` or `1` is read
+                for src in [a, b].into_iter().filter(|src| constant_source(src.node)) {
+                    if arch.reg_code(src.node).is_none() {
+                        let report = miette!(
+                            labels = vec![LabeledSpan::at(src.span.clone(), "Here")],
+                            help = "`0` and `1` are read from the Z and O registers",
+                            "Error: No {} register on the targeted architecture",
+                            src.node
+                        );
+                        errors.push(report);
+                    }
+                }
+                if op.node == Op::Not {
                     let report = miette!(
                         labels = vec![
-                            LabeledSpan::at(spanc.clone(), "This"),
-                            LabeledSpan::at(spana.clone(), "and this are incompatible"),
+                            LabeledSpan::at(a.span.clone(), "Excessive operand"),
+                            LabeledSpan::at(op.span.clone(), "with operation ~"),
                         ],
-                        "Error Can't change V value when reading *V"
+                        help = format!("Try removing {:?}", a.node),
+                        "Error Too many operand for ~"
                     );
                     errors.push(report);
                 }
-                i += 3;
-                adr += 16;
-                inst_mode_format(OpOrCond::Operation(Op::Or), *rega, Reg::Zero, *regc)
+                Instruction::Alu {
+                    op: op.node,
+                    a: a.node,
+                    b: b.node,
+                    dest: dest.node,
+                }
             }
-            // A <- ~D, tested
-            [(Ok(Register(regc)), _), (Ok(Assignement), _), (Ok(Operation(op)), _), (Ok(Register(rega)), _), _] =>
-            {
-                if *op != Op::Not {
-                    panic!("Expected a not operation");
+            Statement::Load { value, .. } if from_sources(statement, arch).is_some() => {
+                fixups.push(Fixup {
+                    index: words.len(),
+                    expr: value.clone(),
+                    here: addresses[index],
+                    statement: index,
+                    patch: Patch::Read,
+                });
+                from_sources(statement, arch).unwrap()
+            }
+            Statement::Load {
+                dest,
+                value,
+                constant: Some(val),
+            } if *val > arch.max_load_value() => {
+                check_load(arch, dest, &value.node.to_string(), errors);
+                // no note for a rejected load
+                if errors.len() == from {
+                    fixups.push(Fixup {
+                        index: words.len(),
+                        expr: value.clone(),
+                        here: addresses[index],
+                        statement: index,
+                        patch: Patch::WideLoad,
+                    });
+                }
+                let (span, adr) = (span.clone(), addresses[index]);
+                let inst = Instruction::Load(0);
+                words.push(Placed { inst, span: span.clone(), adr });
+                words.push(Placed { inst, span, adr: adr + per_word });
+                trace(errors, from, &program.calls(index));
+                continue;
+            }
+            Statement::Load { dest, value, .. } => {
+                check_load(arch, dest, &value.node.to_string(), errors);
+                match value.node {
+                    // bigger values are reported with the expressions
+                    Expr::Value(val) if val <= arch.max_load_value() => Instruction::Load(val),
+                    _ => {
+                        fixups.push(Fixup {
+                            index: words.len(),
+                            expr: value.clone(),
+                            here: addresses[index],
+                            statement: index,
+                            patch: Patch::Load,
+                        });
+                        Instruction::Load(0)
+                    }
                 }
-                if *regc == Reg::A && *rega == Reg::AStar {
-                    panic!("Cannot change A value when reading *A");
+            }
+            Statement::Transfer { dest, src } => {
+                check_conflict(arch, dest, src.node, src.span.clone(), errors);
+                Instruction::Alu {
+                    op: Op::Or,
+                    a: src.node,
+                    b: Reg::Zero,
+                    dest: dest.node,
                 }
-                if *regc == Reg::V && *rega == Reg::VStar {
-                    panic!("Cannot change V value when reading *V");
+            }
+            Statement::Not { dest, src } => {
+                check_conflict(arch, dest, src.node, src.span.clone(), errors);
+                Instruction::Alu {
+                    op: Op::Not,
+                    a: src.node,
+                    b: Reg::A,
+                    dest: dest.node,
                 }
-                i += 4;
-                adr += 16;
-                inst_mode_format(OpOrCond::Operation(*op), *rega, Reg::A, *regc)
             }
-            // D>=, tested
             /*
             A <- main
             D>= (<=> D >= 0 ?)
             JMP
              */
-            [(Ok(Register(rega)), _), (Ok(Condition(cond)), _), _, _, _] => {
-                i += 2;
-                adr += 16;
-                inst_mode_format(OpOrCond::Condition(*cond), *rega, Reg::Zero, Reg::Zero)
-            }
-            // JMP, tested
-            [(Ok(Condition(Cond::Jump)), _), _, _, _, _] => {
-                i += 1;
-                adr += 16;
-                format!("0111000000000000")
-            }
-            // label:, tested
-            [(Ok(Identifier(id)), span), (Ok(Directive(Dir::Label)), _), _, _, _] => {
-                i += 2;
-
-                let id_ref = id_collect.get(id);
-
-                if id_ref == None {
-                    id_collect.insert(
-                        id.clone(),
-                        ColType {
-                            val: adr,
-                            span: span.clone(),
-                            visited: false,
-                        },
-                    );
-                } else {
-                    let other_span = (*id_ref.unwrap()).span.clone();
-                    let report = miette!(
-                        labels = vec![
-                            LabeledSpan::at(other_span, "previously declared here"),
-                            LabeledSpan::at(span.clone(), "declared there"),
-                        ],
-                        "Error: Identifier already declared"
-                    );
-                    errors.push(report);
+            Statement::Condition { reg, cond } => Instruction::Condition {
+                cond: cond.node,
+                reg: reg.node,
+            },
+            Statement::Jump => Instruction::Jump,
+            Statement::Word(values) => {
+                for (i, value) in values.iter().enumerate() {
+                    let adr = addresses[index] + i * per_word;
+                    let inst = match value.node {
+                        Expr::Value(val) => Instruction::Data(val),
+                        _ => {
+                            fixups.push(Fixup {
+                                index: words.len(),
+                                expr: value.clone(),
+                                here: adr,
+                                statement: index,
+                                patch: Patch::Data,
+                            });
+                            Instruction::Data(0)
+                        }
+                    };
+                    let span = value.span.clone();
+                    words.push(Placed { inst, span, adr });
                 }
+                continue;
+            }
+            Statement::Label(_)
+            | Statement::Define { .. }
+            | Statement::Arch(_)
+            | Statement::Org(_)
+            | Statement::Section(_)
+            | Statement::Entry(_) => continue,
+        };
+
+        trace(errors, from, &program.calls(index));
+        let (span, adr) = (span.clone(), addresses[index]);
+        words.push(Placed { inst, span, adr });
+    }
+
+    (words, fixups)
+}
+
+/// last pass, put each word at its address, the gaps are filled with
+/// `fill` and the words placed on others are reported
+fn place(
+    words: &[Placed],
+    arch: &dyn Architecture,
+    fill: u16,
+    errors: &mut Vec<Error>,
+) -> (Vec<Instruction>, Vec<Range<usize>>) {
+    let per_word = arch.address_unit().per_word() as usize;
+    let mut sorted: Vec<&Placed> = words.iter().collect();
+    sorted.sort_by_key(|word| word.adr);
+
+    let mut instructions = vec![];
+    let mut spans: Vec<Range<usize>> = vec![];
+    // address index of the last overlapping word
+    let mut overlapping: Option<usize> = None;
+    for word in sorted {
+        let index = word.adr / per_word;
+        // sorted by address, only the last word can be overlapped
+        if index < instructions.len() {
+            // a single report for consecutive addresses
+            if overlapping.is_none_or(|last| last + 1 < index) {
+                let report = miette!(
+                    labels = vec![
+                        LabeledSpan::at(spans[index].clone(), "placed here"),
+                        LabeledSpan::at(word.span.clone(), "and there"),
+                    ],
+                    help = "Move one of them with ORG or the section layout",
+                    "Error: Two words are placed at address {}",
+                    word.adr
+                );
+                errors.push(report);
+            }
+            overlapping = Some(index);
+            continue;
+        }
+        // the words filling a gap have no source
+        instructions.resize(index, Instruction::Data(fill));
+        spans.resize(index, 0..0);
+        instructions.push(word.inst);
+        spans.push(word.span.clone());
+    }
+
+    (instructions, spans)
+}
 
-                String::new()
+/// patch the loads of expressions, every use of an unknown identifier
+/// is labelled in a single report, returns the notes on the loads made
+/// of several instructions with the address of the first one
+fn resolve(
+    words: &mut [Placed],
+    fixups: &[Fixup],
+    program: &Program,
+    id_collect: &mut HashMap<String, ColType>,
+    arch: &dyn Architecture,
+    errors: &mut Vec<Error>,
+) -> Vec<(usize, String)> {
+    let mut unknown = vec![];
+    let mut notes = vec![];
+
+    for fixup in fixups {
+        let from = errors.len();
+        let calls = program.calls(fixup.statement);
+        let max = arch.max_load_value();
+        let span = fixup.expr.span.clone();
+        match (eval(&fixup.expr, fixup.here, id_collect), fixup.patch) {
+            (Ok(val), Patch::Load) if (0..=max as i64).contains(&val) => {
+                words[fixup.index].inst = Instruction::Load(val as u16)
             }
-            // DEFINE mask 0x1, tested
-            [(Ok(Directive(Dir::Define)), _), (Ok(Identifier(id)), span), (Ok(Value(val)), _), _, _] =>
-            {
-                i += 3;
-
-                let id_ref = id_collect.get(id);
-
-                if id_ref == None {
-                    id_collect.insert(
-                        id.clone(),
-                        ColType {
-                            val: *val,
-                            span: span.clone(),
-                            visited: false,
-                        },
+            (Ok(val), Patch::Load) => errors.push(out_of_range(span, val, max)),
+            (Ok(val), Patch::Data) => match word(val) {
+                Some(val) => words[fixup.index].inst = Instruction::Data(val),
+                None => errors.push(out_of_word(span, val)),
+            },
+            (Ok(_), Patch::Read) => (),
+            (Ok(val), Patch::WideLoad) => match word(val).and_then(|word| wide_load(word, arch)) {
+                Some(sequence) => {
+                    let note = format!(
+                        "{} doesn't fit a data mode word, it is loaded by {} instructions",
+                        fixup.expr.node,
+                        sequence.len()
                     );
-                } else {
-                    let other_span = (*id_ref.unwrap()).span.clone();
                     let report = miette!(
-                        labels = vec![
-                            LabeledSpan::at(other_span, "previously declared here"),
-                            LabeledSpan::at(span.clone(), "declared there"),
-                        ],
-                        "Error: Identifier already declared"
+                        severity = Severity::Advice,
+                        labels = vec![LabeledSpan::at(span, format!("loads 0x{:x}", val as u16))],
+                        "Note: {note}"
                     );
                     errors.push(report);
+                    notes.push((fixup.here, note));
+                    for (offset, inst) in sequence.into_iter().enumerate() {
+                        words[fixup.index + offset].inst = inst;
+                    }
                 }
-
-                String::new()
-            }
-            [(Ok(Comment), _), _, _, _, _] => {
-                i += 1;
-                String::new()
+                None => errors.push(out_of_range(span, val, max)),
+            },
+            // each expansion has its own report
+            (Err(EvalError::Unknown(uses)), _) if !calls.is_empty() => {
+                errors.extend(unknown_reports(uses))
             }
-            _ => panic!("Unexpected Error"),
-        };
-
-        if inst_word != "" {
-            bit_stream_with_id.push(inst_word);
+            (Err(EvalError::Unknown(uses)), _) => unknown.extend(uses),
+            (Err(EvalError::Invalid(e)), _) => errors.push(e),
         }
+        trace(errors, from, &calls);
     }
 
-    if debug {
-        println!("{:.?}", id_collect);
-    }
+    errors.extend(unknown_reports(unknown));
+    notes
+}
 
-    fn handle_id(
-        word: String,
-        col: &mut HashMap<String, ColType>,
-        errs: &mut Vec<Error>,
-    ) -> String {
-        if word.chars().last().unwrap() == '#' {
-            let mut splited_word = word.split("#");
-            let id = splited_word.next().unwrap();
-            let start: usize = splited_word
-                .next()
-                .expect("unable to parse span")
-                .parse()
-                .unwrap();
-            let end: usize = splited_word
-                .next()
-                .expect("unable to parse span")
-                .parse()
-                .unwrap();
-            return if let Some(context) = col.get_mut(id) {
-                context.visited = true;
-                data_mode_format(context.val)
-            } else {
-                let report = miette!(
-                    labels = vec![LabeledSpan::at(start..end, "unknown id"),],
-                    "Error: Unrecognized identifier {id}"
-                );
-                errs.push(report);
-                "E".to_string()
-            };
-        }
+/// whether a source register holds a constant, `0` and `1` are read from them
+fn constant_source(reg: Reg) -> bool {
+    matches!(reg, Reg::Zero | Reg::One)
+}
 
-        word
+/// report the registers, operations and conditions missing from the
+/// targeted architecture, it is only known once the `.arch` are applied
+fn check_names(
+    statement: &Statement,
+    span: &Range<usize>,
+    arch: &dyn Architecture,
+    errors: &mut Vec<Error>,
+) {
+    let not = Spanned::new(Op::Not, span.clone());
+    let (regs, ops, conds) = match statement {
+        Statement::Load { dest, .. } => (vec![dest], vec![], vec![]),
+        Statement::Transfer { dest, src } => (vec![dest, src], vec![], vec![]),
+        Statement::Not { dest, src } => (vec![dest, src], vec![not], vec![]),
+        // a missing Z or O is reported with the operation
+        Statement::Binary { dest, a, op, b } => {
+            let sources = [a, b].into_iter().filter(|src| !constant_source(src.node));
+            (vec![dest].into_iter().chain(sources).collect(), vec![op.clone()], vec![])
+        }
+        Statement::Condition { reg, cond } => (vec![reg], vec![], vec![cond.clone()]),
+        Statement::Jump => (vec![], vec![], vec![Spanned::new(Cond::Jump, span.clone())]),
+        _ => return,
+    };
+    for reg in regs {
+        if let Err(e) = reg.node.supported(arch) {
+            errors.push(e.report(reg.span.clone()));
+        }
     }
-
-    let bit_stream: Vec<String> = bit_stream_with_id
-        .into_iter()
-        .map(|s| handle_id(s, &mut id_collect, &mut errors))
-        .collect();
-
-    for (key, context) in id_collect.clone() {
-        match context {
-            ColType {
-                val: _,
-                span,
-                visited: false,
-            } => {
-                let report = miette!(
-                    severity = Severity::Warning,
-                    labels = vec![LabeledSpan::at(span, "Here"),],
-                    "Error: {key} declared but never used"
-                );
-                errors.push(report);
-            }
-            _ => (),
+    for op in ops {
+        if let Err(e) = op.node.supported(arch) {
+            errors.push(e.report(op.span));
+        }
+    }
+    for cond in conds {
+        if let Err(e) = cond.node.supported(arch) {
+            errors.push(e.report(cond.span));
         }
     }
+}
+
+/// ALU instruction building a constant from the Z and O sources, for a load
+/// into another register than the load register or too big for a data mode word
+fn from_sources(statement: &Statement, arch: &dyn Architecture) -> Option<Instruction> {
+    let Statement::Load {
+        dest,
+        constant: Some(val),
+        ..
+    } = statement
+    else {
+        return None;
+    };
+    if dest.node == arch.load_register() && *val <= arch.max_load_value() {
+        return None;
+    }
+    let (op, a, b) = match val {
+        0 => (Op::Or, Reg::Zero, Reg::Zero),
+        1 => (Op::Or, Reg::One, Reg::Zero),
+        2 => (Op::Add, Reg::One, Reg::One),
+        0xfffe => (Op::Not, Reg::One, Reg::A),
+        0xffff => (Op::Sub, Reg::Zero, Reg::One),
+        _ => return None,
+    };
+    let supported = arch.op_code(op).is_some()
+        && arch.reg_code(a).is_some()
+        && arch.reg_code(b).is_some();
+    supported.then_some(Instruction::Alu {
+        op,
+        a,
+        b,
+        dest: dest.node,
+    })
+}
+
+/// instructions loading a value bigger than a data mode word, its complement
+/// or its half is loaded then turned into the value by the ALU
+fn wide_load(val: u16, arch: &dyn Architecture) -> Option<[Instruction; 2]> {
+    let (max, load) = (arch.max_load_value(), arch.load_register());
+    let alu = |op| Instruction::Alu {
+        op,
+        a: load,
+        b: load,
+        dest: load,
+    };
+    if !val <= max && arch.op_code(Op::Not).is_some() {
+        Some([Instruction::Load(!val), alu(Op::Not)])
+    } else if val.is_multiple_of(2) && val / 2 <= max && arch.op_code(Op::Add).is_some() {
+        Some([Instruction::Load(val / 2), alu(Op::Add)])
+    } else {
+        None
+    }
+}
 
-    ParserReport {
-        bit_stream: bit_stream.join(sep),
-        report: errors,
-        id_collect: id_collect,
+/// add an identifier to the collection, reporting a duplicate declaration,
+/// returns whether it was added
+fn declare(
+    id_collect: &mut HashMap<String, ColType>,
+    id: &Spanned<String>,
+    val: u16,
+    errors: &mut Vec<Error>,
+) -> bool {
+    let other = id_collect.get(&id.node);
+    if other.is_some_and(|other| other.span.is_empty()) {
+        let report = miette!(
+            labels = vec![LabeledSpan::at(id.span.clone(), "declared here")],
+            help = format!("Remove -D {} or wrap the declaration in IFNDEF", id.node),
+            "Error: Identifier already defined on the command line"
+        );
+        errors.push(report);
+        false
+    } else if let Some(other) = other {
+        let report = miette!(
+            labels = vec![
+                LabeledSpan::at(other.span.clone(), "previously declared here"),
+                LabeledSpan::at(id.span.clone(), "declared there"),
+            ],
+            "Error: Identifier already declared"
+        );
+        errors.push(report);
+        false
+    } else {
+        id_collect.insert(
+            id.node.clone(),
+            ColType {
+                val,
+                span: id.span.clone(),
+                visited: false,
+            },
+        );
+        true
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::lexer::spec::arch_v1::ArchV1;
+    use crate::render;
     use logos::Logos;
 
+    fn bit_stream(parser_report: ParserReport) -> String {
+        colored::control::set_override(false);
+        render::bit_stream(&parser_report.instructions, &ArchV1, "\n")
+    }
+
+    fn assemble(src: &str) -> ParserReport {
+        let tokens: Vec<(Result<Token, LexError>, std::ops::Range<usize>)> = Token::lexer(src).spanned().collect();
+        parse(&tokens, &mut Loader::default(), &mut Targets::default(), &[], &SectionLayout::default(), false)
+    }
+
+    /// `src` assembles into the same words as the already placed `placed`
+    fn assert_same_words(src: &str, placed: &str) {
+        assert_eq!(bit_stream(assemble(placed)), bit_stream(assemble(src)), "{src}");
+    }
+
     #[test]
     fn test_define() {
         let src = "DEFINE foo 0\nDEFINE bar 1\nDEFINE titi 42\nDEFINE tata 73";
@@ -368,16 +2487,14 @@ mod tests {
             },
         );
 
-        let lex = Token::lexer(src);
-
-        let mut tokens: Vec<(Result<Token, ()>, std::ops::Range<usize>)> = lex.spanned().collect();
+        let tokens: Vec<(Result<Token, LexError>, std::ops::Range<usize>)> = Token::lexer(src).spanned().collect();
         assert_eq!(
             collection,
-            parse(&mut tokens, false, false, "").id_collect
+            parse(&tokens, &mut Loader::default(), &mut Targets::default(), &[], &SectionLayout::default(), false).id_collect
         );
         assert_eq!(
             collection,
-            parse(&mut tokens, false, true, "").id_collect
+            parse(&tokens, &mut Loader::default(), &mut Targets::default(), &[], &SectionLayout::default(), true).id_collect
         );
     }
 
@@ -387,16 +2504,17 @@ mod tests {
         let mut collection: HashMap<String, ColType> = HashMap::new();
         collection.insert(
             "main".to_string(),
+            // the entry point is used by the cpu
             ColType {
                 val: 0,
                 span: 0..4,
-                visited: false,
+                visited: true,
             },
         );
         collection.insert(
             "label".to_string(),
             ColType {
-                val: 16,
+                val: 1,
                 span: 10..15,
                 visited: false,
             },
@@ -404,22 +2522,20 @@ mod tests {
         collection.insert(
             "titi".to_string(),
             ColType {
-                val: 144,
+                val: 9,
                 span: 49..53,
                 visited: false,
             },
         );
 
-        let lex = Token::lexer(src);
-
-        let mut tokens: Vec<(Result<Token, ()>, std::ops::Range<usize>)> = lex.spanned().collect();
+        let tokens: Vec<(Result<Token, LexError>, std::ops::Range<usize>)> = Token::lexer(src).spanned().collect();
         assert_eq!(
             collection,
-            parse(&mut tokens, false, false, "").id_collect
+            parse(&tokens, &mut Loader::default(), &mut Targets::default(), &[], &SectionLayout::default(), false).id_collect
         );
         assert_eq!(
             collection,
-            parse(&mut tokens, false, true, "").id_collect
+            parse(&tokens, &mut Loader::default(), &mut Targets::default(), &[], &SectionLayout::default(), true).id_collect
         );
     }
 
@@ -428,28 +2544,45 @@ mod tests {
         let src = "DEFINE mask 42\nA = 0\nA = 0x7fff\nA = mask";
         let expected = "1000000000000000\n1111111111111111\n1000000000101010";
 
-        let lex = Token::lexer(src);
-
-        let mut tokens: Vec<(Result<Token, ()>, std::ops::Range<usize>)> = lex.spanned().collect();
+        let tokens: Vec<(Result<Token, LexError>, std::ops::Range<usize>)> = Token::lexer(src).spanned().collect();
 
         assert_eq!(
             expected,
-            parse(&mut tokens, false, false, "\n").bit_stream
+            bit_stream(parse(&tokens, &mut Loader::default(), &mut Targets::default(), &[], &SectionLayout::default(), false))
         );
         assert_eq!(
             expected,
-            parse(&mut tokens, false, true, "\n").bit_stream
+            bit_stream(parse(&tokens, &mut Loader::default(), &mut Targets::default(), &[], &SectionLayout::default(), true))
         );
     }
 
     #[test]
-    fn test_load_value_into_wrong_register() {
-        let src = "D = 0";
+    fn test_constant_sources() {
+        let src = "DEFINE one 1\nD = D + 1\nD = D - 1\nD = 0\n*A = one\nV = 2\nD = -1\nD = ~1\nA = -1\nD = 1 - D\nA = 1 - 1";
+        let placed = "D = D + O\nD = D - O\nD = Z\n*A = O\nV = O + O\nD = Z - O\nD = ~O\nA = Z - O\nD = O - D\nA = 0";
 
-        let lex = Token::lexer(src);
+        let parser_report = assemble(src);
+        assert!(parser_report.report.is_empty());
+
+        assert_same_words(src, placed);
+
+        let src = "D = D + 2\nD = 3";
+        let parser_report = assemble(src);
+        let messages: Vec<String> = parser_report.report.iter().map(|e| e.to_string()).collect();
+        assert_eq!(
+            vec![
+                "Error: Expected one of register, `0`, `1`",
+                "Error Can't push direct value into other register than A",
+            ],
+            messages
+        );
+    }
+
+    #[test]
+    fn test_load_value_into_wrong_register() {
+        let src = "D = 5";
 
-        let mut tokens: Vec<(Result<Token, ()>, std::ops::Range<usize>)> = lex.spanned().collect();
-        assert!(!parse(&mut tokens, false, false, "")
+        assert!(!assemble(src)
             .report
             .is_empty());
     }
@@ -460,17 +2593,15 @@ mod tests {
 
         let expected = "0011000000110100\n0011000100110100\n0011000001110100\n0011000100110000\n0011000100110001\n0011000000110001";
 
-        let lex = Token::lexer(src);
-
-        let mut tokens: Vec<(Result<Token, ()>, std::ops::Range<usize>)> = lex.spanned().collect();
+        let tokens: Vec<(Result<Token, LexError>, std::ops::Range<usize>)> = Token::lexer(src).spanned().collect();
 
         assert_eq!(
             expected,
-            parse(&mut tokens, false, false, "\n").bit_stream
+            bit_stream(parse(&tokens, &mut Loader::default(), &mut Targets::default(), &[], &SectionLayout::default(), false))
         );
         assert_eq!(
             expected,
-            parse(&mut tokens, false, true, "\n").bit_stream
+            bit_stream(parse(&tokens, &mut Loader::default(), &mut Targets::default(), &[], &SectionLayout::default(), true))
         );
     }
 
@@ -478,10 +2609,7 @@ mod tests {
     fn test_wrong_register_transfer() {
         let src = "A = *A\nV = *V";
 
-        let lex = Token::lexer(src);
-
-        let mut tokens: Vec<(Result<Token, ()>, std::ops::Range<usize>)> = lex.spanned().collect();
-        assert!(!parse(&mut tokens, false, false, "")
+        assert!(!assemble(src)
             .report
             .is_empty())
     }
@@ -492,17 +2620,15 @@ mod tests {
 
         let expected = "0010000100110110\n0011000100110110\n0011000001110110";
 
-        let lex = Token::lexer(src);
-
-        let mut tokens: Vec<(Result<Token, ()>, std::ops::Range<usize>)> = lex.spanned().collect();
+        let tokens: Vec<(Result<Token, LexError>, std::ops::Range<usize>)> = Token::lexer(src).spanned().collect();
 
         assert_eq!(
             expected,
-            parse(&mut tokens, false, false, "\n").bit_stream
+            bit_stream(parse(&tokens, &mut Loader::default(), &mut Targets::default(), &[], &SectionLayout::default(), false))
         );
         assert_eq!(
             expected,
-            parse(&mut tokens, false, true, "\n").bit_stream
+            bit_stream(parse(&tokens, &mut Loader::default(), &mut Targets::default(), &[], &SectionLayout::default(), true))
         );
     }
 
@@ -512,17 +2638,15 @@ mod tests {
 
         let expected = "0111000000000000";
 
-        let lex = Token::lexer(src);
-
-        let mut tokens: Vec<(Result<Token, ()>, std::ops::Range<usize>)> = lex.spanned().collect();
+        let tokens: Vec<(Result<Token, LexError>, std::ops::Range<usize>)> = Token::lexer(src).spanned().collect();
 
         assert_eq!(
             expected,
-            parse(&mut tokens, false, false, "\n").bit_stream
+            bit_stream(parse(&tokens, &mut Loader::default(), &mut Targets::default(), &[], &SectionLayout::default(), false))
         );
         assert_eq!(
             expected,
-            parse(&mut tokens, false, true, "\n").bit_stream
+            bit_stream(parse(&tokens, &mut Loader::default(), &mut Targets::default(), &[], &SectionLayout::default(), true))
         );
     }
 
@@ -532,30 +2656,25 @@ mod tests {
 
         let expected = "0101000100000000\n0101000010000100";
 
-        let lex = Token::lexer(src);
-
-        let mut tokens: Vec<(Result<Token, ()>, std::ops::Range<usize>)> = lex.spanned().collect();
+        let tokens: Vec<(Result<Token, LexError>, std::ops::Range<usize>)> = Token::lexer(src).spanned().collect();
 
         assert_eq!(
             expected,
-            parse(&mut tokens, false, false, "\n").bit_stream
+            bit_stream(parse(&tokens, &mut Loader::default(), &mut Targets::default(), &[], &SectionLayout::default(), false))
         );
         assert_eq!(
             expected,
-            parse(&mut tokens, false, true, "\n").bit_stream
+            bit_stream(parse(&tokens, &mut Loader::default(), &mut Targets::default(), &[], &SectionLayout::default(), true))
         );
     }
 
     #[test]
-    #[should_panic]
     fn test_non_single_operand_operation() {
         let src = "A = +D";
 
-        let lex = Token::lexer(src);
-
-        let mut tokens: Vec<(Result<Token, ()>, std::ops::Range<usize>)> = lex.spanned().collect();
-
-        parse(&mut tokens, false, false, "");
+        assert!(!assemble(src)
+            .report
+            .is_empty());
     }
 
     #[test]
@@ -564,17 +2683,15 @@ mod tests {
 
         let expected = "0000000000100000\n0010000000100000\n0011000001000100";
 
-        let lex = Token::lexer(src);
-
-        let mut tokens: Vec<(Result<Token, ()>, std::ops::Range<usize>)> = lex.spanned().collect();
+        let tokens: Vec<(Result<Token, LexError>, std::ops::Range<usize>)> = Token::lexer(src).spanned().collect();
 
         assert_eq!(
             expected,
-            parse(&mut tokens, false, false, "\n").bit_stream
+            bit_stream(parse(&tokens, &mut Loader::default(), &mut Targets::default(), &[], &SectionLayout::default(), false))
         );
         assert_eq!(
             expected,
-            parse(&mut tokens, false, true, "\n").bit_stream
+            bit_stream(parse(&tokens, &mut Loader::default(), &mut Targets::default(), &[], &SectionLayout::default(), true))
         );
     }
 
@@ -582,11 +2699,7 @@ mod tests {
     fn test_wrong_double_operand_operation() {
         let src = "A = A ~ D";
 
-        let lex = Token::lexer(src);
-
-        let mut tokens: Vec<(Result<Token, ()>, std::ops::Range<usize>)> = lex.spanned().collect();
-
-        assert!(!parse(&mut tokens, false, false, "")
+        assert!(!assemble(src)
             .report
             .is_empty());
     }
@@ -595,12 +2708,701 @@ mod tests {
     fn test_incompatible_registers() {
         let src = "A = A + *A\nV = V + *V\nA = *A & D";
 
-        let lex = Token::lexer(src);
+        assert!(!assemble(src)
+            .report
+            .is_empty());
+    }
+
+    #[test]
+    fn test_lexer_errors() {
+        let src = "A = 0x8000\nB = A\nD = A $ D";
+
+        let parser_report = assemble(src);
+        assert_eq!(3, parser_report.report.len());
+    }
+
+    #[test]
+    fn test_program() {
+        let src = "DEFINE mask 1\nmain: A = mask ; comment\nD = ~A\nD >=\nJMP";
+
+        let tokens: Vec<(Result<Token, LexError>, std::ops::Range<usize>)> = Token::lexer(src).spanned().collect();
+        let (program, errors) = parse_program(&tokens, &mut Loader::default(), &Targets::default(), &[]);
+        assert!(errors.is_empty());
+
+        let spans: Vec<Range<usize>> = program.statements.iter().map(|s| s.span.clone()).collect();
+        assert_eq!(vec![0..13, 14..19, 20..28, 39..45, 46..50, 51..54], spans);
+        assert_eq!(
+            Statement::Not {
+                dest: Spanned::new(Reg::D, 39..40),
+                src: Spanned::new(Reg::A, 44..45),
+            },
+            program.statements[3].node
+        );
+    }
+
+    #[test]
+    fn test_syntax_error_recovery() {
+        // every wrong line is reported and the other ones are still assembled
+        let src = "A = 1\nD = A + 3\nA = 2\n= D\nD = D + A\nfoo\nJMP";
+
+        let tokens: Vec<(Result<Token, LexError>, std::ops::Range<usize>)> = Token::lexer(src).spanned().collect();
+        let (program, errors) = parse_program(&tokens, &mut Loader::default(), &Targets::default(), &[]);
+        assert_eq!(3, errors.len());
+        assert_eq!(4, program.statements.len());
+
+        let parser_report = parse(&tokens, &mut Loader::default(), &mut Targets::default(), &[], &SectionLayout::default(), false);
+        assert_eq!(3, parser_report.report.len());
+        assert_eq!(4, parser_report.instructions.len());
+    }
+
+    #[test]
+    fn test_one_statement_per_line() {
+        for src in ["A = D D = A", "D = A +\nA = 5", "D !=\n\nJMP JMP", "main: foo:\nJMP\nJMP main"] {
+            let tokens: Vec<(Result<Token, LexError>, std::ops::Range<usize>)> = Token::lexer(src).spanned().collect();
+            let (_, errors) = parse_program(&tokens, &mut Loader::default(), &Targets::default(), &[]);
+            assert_eq!(1, errors.len(), "{src}");
+        }
+
+        let src = "\n\nmain: ; entry point\n\nA = main ; loop\nJMP\n";
+
+        let tokens: Vec<(Result<Token, LexError>, std::ops::Range<usize>)> = Token::lexer(src).spanned().collect();
+        let (program, errors) = parse_program(&tokens, &mut Loader::default(), &Targets::default(), &[]);
+        assert!(errors.is_empty());
+        assert_eq!(3, program.statements.len());
+    }
+
+    #[test]
+    fn test_forward_reference() {
+        let src = "A = end\nJMP\nA = foo\nA = bar\nA = foo\nend:\nDEFINE bar 3";
+        let expected = "1000000000000101\n0111000000000000\n1000000000000000\n1000000000000011\n1000000000000000";
+
+        let parser_report = assemble(src);
+
+        // both uses of foo are in the same report
+        assert_eq!(1, parser_report.report.len());
+        let labels = parser_report.report[0].labels().unwrap().count();
+        assert_eq!(2, labels);
+        assert_eq!(expected, bit_stream(parser_report));
+    }
+
+    #[test]
+    fn test_address_unit() {
+        let src = "JMP\nJMP\nend:\nA = end";
+
+        let tokens: Vec<(Result<Token, LexError>, std::ops::Range<usize>)> = Token::lexer(src).spanned().collect();
+        assert_eq!(2, parse(&tokens, &mut Loader::default(), &mut Targets::default(), &[], &SectionLayout::default(), false).id_collect["end"].val);
+
+        let isa = include_str!("../examples/arch_v1.toml").replace("\"word\"", "\"byte\"");
+        let mut targets = Targets::default();
+        targets.add(Box::new(isa::parse("arch_v1.toml", &isa).unwrap()));
+        targets.select("v1_file");
+        assert_eq!(4, parse(&tokens, &mut Loader::default(), &mut targets, &[], &SectionLayout::default(), false).id_collect["end"].val);
+
+        // the label doesn't fit a data mode word, it is loaded by two instructions
+        let src = "JMP\n".repeat(0x8000) + "end:\nA = end";
+
+        let tokens: Vec<(Result<Token, LexError>, std::ops::Range<usize>)> = Token::lexer(&src).spanned().collect();
+        let parser_report = parse(&tokens, &mut Loader::default(), &mut Targets::default(), &[], &SectionLayout::default(), false);
+        let messages: Vec<String> = parser_report.report.iter().map(|e| e.to_string()).collect();
+        assert_eq!(
+            vec!["Note: end doesn't fit a data mode word, it is loaded by 2 instructions"],
+            messages
+        );
+        let load = [Instruction::Load(0x7fff), Instruction::Alu { op: Op::Not, a: Reg::A, b: Reg::A, dest: Reg::A }];
+        assert_eq!(load, parser_report.instructions[0x8000..]);
+
+        // past the address space
+        let tokens: Vec<(Result<Token, LexError>, std::ops::Range<usize>)> = Token::lexer("ORG 0xffff\nJMP\nend:\nA = end").spanned().collect();
+        let parser_report = parse(&tokens, &mut Loader::default(), &mut Targets::default(), &[], &SectionLayout::default(), false);
+        let messages: Vec<String> = parser_report.report.iter().map(|e| e.to_string()).collect();
+        assert!(messages.contains(&"Error: Label end is out of range".to_string()));
+    }
+
+    #[test]
+    fn test_expression() {
+        let src = "start:\nDEFINE mask 1 << 8 | 0x3\nA = ~mask & 0x7fff\nA = (end - start) * 2 - 1\nA = $ + 2 * 3 % 4\nA = 10 / 3 - -1\nend:";
+        let expected = "1111111011111100\n1000000000000111\n1000000000000100\n1000000000000100";
+
+        let parser_report = assemble(src);
+        assert!(parser_report.report.is_empty());
+        assert_eq!(expected, bit_stream(parser_report));
+    }
+
+    #[test]
+    fn test_mask_composition() {
+        let src = "DEFINE movement_mask 0b11 << 9\nDEFINE left 0x8\nDEFINE ob_detection_mask 1 << 8\nA = movement_mask | left\nD = A\nA = ob_detection_mask\nA = movement_mask & ~left";
+        let placed = "A = 0x608\nD = A\nA = 0x100\nA = 0x600";
+
+        let parser_report = assemble(src);
+        assert!(parser_report.report.is_empty());
+
+        assert_same_words(src, placed);
+    }
+
+    #[test]
+    fn test_wrong_expression() {
+        let src = "A = 1 / (2 - 2)\nA = 1 << 16\nA = 0xffff + 1\nA = 0 - 0x8001\nA = (1 + 2\nA = foo + bar * foo\nDEFINE big 0xff << 9\nA = big";
+
+        let parser_report = assemble(src);
+        // a single report labels both uses of foo
+        assert_eq!(8, parser_report.report.len());
+    }
+
+    #[test]
+    fn test_define_chain() {
+        let src = "DEFINE c b + 1\nDEFINE b a * 2\nDEFINE a end\nA = c\nJMP\nend:";
+        let expected = "1000000000000101\n0111000000000000";
+
+        let parser_report = assemble(src);
+        assert!(parser_report.report.is_empty());
+        assert_eq!(expected, bit_stream(parser_report));
+    }
+
+    #[test]
+    fn test_circular_define() {
+        let src = "DEFINE a b + 1\nDEFINE b c\nDEFINE c a\nDEFINE d a\nDEFINE e e\nA = d";
+
+        let parser_report = assemble(src);
+        // d only reads the cycle, it isn't reported on its own
+        let messages: Vec<String> = parser_report.report.iter().map(|e| e.to_string()).collect();
+        assert_eq!(
+            vec![
+                "Error: Circular definition a -> b -> c -> a",
+                "Error: Circular definition e -> e"
+            ],
+            messages
+        );
+    }
+
+    #[test]
+    fn test_macro() {
+        let src = "MACRO load reg, value\nA = value\nreg = A\nENDM\nload D, 1 << 2\nstart: load *A, start + 1";
+        // same program without the macro
+        let expanded = "A = 1 << 2\nD = A\nA = 3\n*A = A";
+
+        let parser_report = assemble(src);
+        assert!(parser_report.report.is_empty());
+
+        assert_same_words(src, expanded);
+    }
+
+    #[test]
+    fn test_macro_local_labels() {
+        let src = "MACRO skip\nA = next\nJMP\nnext:\nENDM\nskip\nskip";
+
+        let tokens: Vec<(Result<Token, LexError>, std::ops::Range<usize>)> = Token::lexer(src).spanned().collect();
+        let (program, errors) = parse_program(&tokens, &mut Loader::default(), &Targets::default(), &[]);
+        assert!(errors.is_empty());
+        assert_eq!(2, program.expansions.len());
+        assert_eq!(0..3, program.expansions[0].statements);
+        assert_eq!(3..6, program.expansions[1].statements);
+
+        let parser_report = parse(&tokens, &mut Loader::default(), &mut Targets::default(), &[], &SectionLayout::default(), false);
+        assert!(parser_report.report.is_empty());
+        assert_eq!(2, parser_report.id_collect["next#1"].val);
+        assert_eq!(4, parser_report.id_collect["next#2"].val);
+    }
+
+    #[test]
+    fn test_nested_macro() {
+        let src = "MACRO inner\nA = 1\nENDM\nMACRO outer\ninner\nJMP\nENDM\nouter";
+
+        let tokens: Vec<(Result<Token, LexError>, std::ops::Range<usize>)> = Token::lexer(src).spanned().collect();
+        let (program, errors) = parse_program(&tokens, &mut Loader::default(), &Targets::default(), &[]);
+        assert!(errors.is_empty());
+        // the inner expansion comes first
+        let names: Vec<&str> = program.calls(0).iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(vec!["inner", "outer"], names);
+        let names: Vec<&str> = program.calls(1).iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(vec!["outer"], names);
+    }
+
+    #[test]
+    fn test_wrong_macro() {
+        let src = "MACRO load value\nA = value\nENDM\nload\nload 1, 2\nload foo\nload 0xffff + 1\nMACRO load\nENDM\nMACRO loop\nloop\nENDM\nloop\nMACRO open";
+
+        let parser_report = assemble(src);
+        let messages: Vec<String> = parser_report.report.iter().map(|e| e.to_string()).collect();
+        assert_eq!(
+            vec![
+                "Error: Macro load takes 1 arguments",
+                "Error: Macro load takes 1 arguments",
+                "Error: Macro load already defined",
+                "Error: Too many nested expansions of loop",
+                "Error: Missing ENDM",
+                "Error: Unrecognized identifier foo",
+                "Error: Value out of range",
+            ],
+            messages
+        );
+        // the call is labelled along with the body
+        let labels = parser_report.report[5].labels().unwrap().count();
+        assert_eq!(2, labels);
+    }
+
+    #[test]
+    fn test_conditional() {
+        let src = "DEFINE port 0x10\nIFNDEF BOARD\nDEFINE BOARD 1\nENDIF\nIF BOARD == 2\nA = 2\nELSEIF BOARD > 2 & BOARD <= 4\nA = 3\nELSE\nA = 1\nIF 1\nA = 4\nENDIF\nENDIF\nIFDEF DEBUG\nA = port\nENDIF";
+        let cases: [(&[(String, u16)], &str); 4] = [
+            (&[], "1000000000000001\n1000000000000100"),
+            (&[("BOARD".to_string(), 2)], "1000000000000010"),
+            (&[("BOARD".to_string(), 4)], "1000000000000011"),
+            (&[("DEBUG".to_string(), 0)], "1000000000000001\n1000000000000100\n1000000000010000"),
+        ];
+
+        let tokens: Vec<(Result<Token, LexError>, std::ops::Range<usize>)> = Token::lexer(src).spanned().collect();
+        for (defines, expected) in cases {
+            let parser_report = parse(&tokens, &mut Loader::default(), &mut Targets::default(), defines, &SectionLayout::default(), false);
+            // port is read by a skipped line, it isn't unused
+            assert!(parser_report.report.is_empty());
+            assert_eq!(expected, bit_stream(parser_report));
+        }
+    }
+
+    #[test]
+    fn test_wrong_conditional() {
+        let src = "ENDIF\nIF 1\nELSE\nELSEIF 1\nENDIF\nIF foo\nA = 1\nENDIF\nIF $\nENDIF\nDEFINE BOARD 2\nIF 1 2\nENDIF\nIFDEF";
+
+        let tokens: Vec<(Result<Token, LexError>, std::ops::Range<usize>)> = Token::lexer(src).spanned().collect();
+        let parser_report = parse(&tokens, &mut Loader::default(), &mut Targets::default(), &[("BOARD".to_string(), 1)], &SectionLayout::default(), false);
+        let messages: Vec<String> = parser_report.report.iter().map(|e| e.to_string()).collect();
+        assert_eq!(
+            vec![
+                "Error: ENDIF without IF",
+                "Error: ELSEIF after ELSE",
+                "Error: Unrecognized identifier foo",
+                "Error: `$` can't be used in a condition",
+                "Error: Expected end of line",
+                "Error: Expected identifier",
+                "Error: Missing ENDIF",
+                "Error: Identifier already defined on the command line",
+            ],
+            messages
+        );
+    }
+
+    #[test]
+    fn test_repetition() {
+        let cases = [
+            ("REPT 3\nD = A\nENDR", "D = A\nD = A\nD = A"),
+            ("REPT 0\nD = A\nENDR\nA = 1", "A = 1"),
+            (
+                "FOR i = 1 TO 3\nA = i * 2\nD = A\nENDF",
+                "A = 2\nD = A\nA = 4\nD = A\nA = 6\nD = A",
+            ),
+            (
+                "FOR i = 0 TO 1\nREPT i + 1\nA = i\nENDR\nENDF",
+                "A = 0\nA = 1\nA = 1",
+            ),
+            (
+                "FOR i = 2 TO 3\nA = next\nJMP\nnext:\nENDF",
+                "A = 2\nJMP\nA = 4\nJMP",
+            ),
+        ];
+
+        for (src, expanded) in cases {
+            let parser_report = assemble(src);
+            assert!(parser_report.report.is_empty(), "{src}");
+
+            assert_same_words(src, expanded);
+        }
+    }
+
+    #[test]
+    fn test_set() {
+        let src = "SET n 1\nA = n\nSET n n + 1\nA = n\nDEFINE size n * 2\nA = size\nSET n 0\nREPT 3\nSET n n + 1\nA = n\nENDR\nIFDEF n\nA = 0\nENDIF";
+
+        let parser_report = assemble(src);
+        assert!(parser_report.report.is_empty());
+        assert_eq!(
+            "1000000000000001\n1000000000000010\n1000000000000100\n1000000000000001\n1000000000000010\n1000000000000011\n1000000000000000",
+            bit_stream(parser_report)
+        );
+    }
+
+    #[test]
+    fn test_wrong_repetition() {
+        let src = "REPT $\nENDR\nFOR i = 0 TO 0x7fff * 3\nENDF\nREPT 1 2\nENDR\nDEFINE c 1\nSET c 2\nSET v 1\nDEFINE v 2\nREPT 1\nA = 1";
+
+        let parser_report = assemble(src);
+        let messages: Vec<String> = parser_report.report.iter().map(|e| e.to_string()).collect();
+        assert_eq!(
+            vec![
+                "Error: `$` can't be used in a repetition count",
+                "Error: Value out of range",
+                "Error: Expected end of line",
+                "Error: Can't SET the constant c",
+                "Error: Identifier already declared",
+                "Error: Missing ENDR",
+                "Error: c declared but never used",
+            ],
+            messages
+        );
+    }
+
+    #[test]
+    fn test_data() {
+        let src = "DEFINE n 2\n.word 1, 0xffff\nstart: .fill n, end - start\n.zero 1\nend: A = table\nJMP\ntable: .word start, end, $";
+
+        let parser_report = assemble(src);
+        assert!(parser_report.report.is_empty());
+        assert_eq!(Instruction::Data(3), parser_report.instructions[2]);
+        assert_eq!(
+            "0000000000000001\n1111111111111111\n0000000000000011\n0000000000000011\n0000000000000000\n1000000000000111\n0111000000000000\n0000000000000010\n0000000000000101\n0000000000001001",
+            bit_stream(parser_report)
+        );
+    }
+
+    #[test]
+    fn test_strings() {
+        let src = "A = 'A'\n.string \"Hi\\n\"\n.stringz \"ok\"\n.string \"abc\", packed\n.stringz \"ab\", packed";
+        let placed = "A = 65\n.word 72, 105, 10\n.word 111, 107, 0\n.word 0x6162, 0x6300\n.word 0x6162, 0";
+
+        let parser_report = assemble(src);
+        assert!(parser_report.report.is_empty());
+
+        assert_same_words(src, placed);
+    }
+
+    #[test]
+    fn test_wrong_strings() {
+        let src = ".string\n.string \"é€\"\n.stringz \"a\", zero\n.string \"\\q\"";
+
+        let parser_report = assemble(src);
+        let messages: Vec<String> = parser_report.report.iter().map(|e| e.to_string()).collect();
+        assert_eq!(
+            vec![
+                "Error: Unknown escape sequence",
+                "Error: Expected string",
+                "Error: Character out of range",
+                "Error: Expected packed",
+            ],
+            messages
+        );
+    }
+
+    #[test]
+    fn test_wide_loads() {
+        let src = "DEFINE mask 0xff_f0\nA = -3\nA = mask\nA = 0o17\nA = end\nJMP\n.word -2, -0x8000\nend: JMP";
+        let placed = "A = 2\nA = ~A\nA = 0xf\nA = ~A\nA = 15\nA = 9\nJMP\n.word 0xfffe, 0x8000\nJMP";
+
+        let parser_report = assemble(src);
+        let messages: Vec<String> = parser_report.report.iter().map(|e| e.to_string()).collect();
+        assert_eq!(
+            vec![
+                "Note: -3 doesn't fit a data mode word, it is loaded by 2 instructions",
+                "Note: mask doesn't fit a data mode word, it is loaded by 2 instructions",
+            ],
+            messages
+        );
+        assert_eq!(parser_report.spans[0], parser_report.spans[1]);
+
+        assert_same_words(src, placed);
+
+        let src = "A = -0x8001\n.word -0x8001, 0x8000 * 2\nA = $ + 0x8000";
+        let parser_report = assemble(src);
+        assert_eq!(4, parser_report.report.len());
+
+        // the size doesn't depend on where the DEFINE is
+        let src = "A = big\nD = one\nA = end\nend: JMP\nDEFINE big 0x9000\nDEFINE one 1";
+        let placed = "A = 0x6fff\nA = ~A\nD = 1\nA = 4\nJMP";
+        let parser_report = assemble(src);
+        let messages: Vec<String> = parser_report.report.iter().map(|e| e.to_string()).collect();
+        assert_eq!(
+            vec!["Note: big doesn't fit a data mode word, it is loaded by 2 instructions"],
+            messages
+        );
+        assert_same_words(src, placed);
+
+        // a rejected load has no note
+        let parser_report = assemble("D = 0x8000");
+        let messages: Vec<String> = parser_report.report.iter().map(|e| e.to_string()).collect();
+        assert_eq!(vec!["Error Can't push direct value into other register than A"], messages);
+    }
+
+    #[test]
+    fn test_wrong_data() {
+        let src = ".word\n.fill $, 1\n.word 0x7fff * 3\n.fill 1\n.word missing\nA = 0xffff + 1";
+
+        let parser_report = assemble(src);
+        let messages: Vec<String> = parser_report.report.iter().map(|e| e.to_string()).collect();
+        assert_eq!(
+            vec![
+                "Error: Expected one of value, identifier, reference, `$`, `(`, `-`, `~`",
+                "Error: `$` can't be used in a fill count",
+                "Error: Expected `,`",
+                "Error: Value out of range",
+                "Error: Value out of range",
+                "Error: Unrecognized identifier missing",
+            ],
+            messages
+        );
+    }
+
+    #[test]
+    fn test_org() {
+        let src = "A = start\nJMP\nORG 4\nstart: D = A";
+        // same program with the gap written out
+        let placed = "A = 4\nJMP\n.zero 2\nD = A";
+
+        let parser_report = assemble(src);
+        assert!(parser_report.report.is_empty());
+        assert!(parser_report.spans[2].is_empty());
+
+        assert_same_words(src, placed);
+    }
+
+    #[test]
+    fn test_sections() {
+        let src = ".data\ntable: .word 1, 2\n.text\nA = table\n.section vectors\n.word start\n.text\nstart: JMP";
+        let placed = ".fill 2, 0x7000\nA = 8\nJMP\n.fill 4, 0x7000\n.word 1, 2, 3";
+        let layout = SectionLayout {
+            bases: vec![("text".to_string(), 2), ("data".to_string(), 8)],
+            fill: 0x7000,
+        };
+
+        let tokens: Vec<(Result<Token, LexError>, std::ops::Range<usize>)> = Token::lexer(src).spanned().collect();
+        let parser_report = parse(&tokens, &mut Loader::default(), &mut Targets::default(), &[], &layout, false);
+        assert!(parser_report.report.is_empty());
 
-        let mut tokens: Vec<(Result<Token, ()>, std::ops::Range<usize>)> = lex.spanned().collect();
+        assert_eq!(bit_stream(assemble(placed)), bit_stream(parser_report));
+    }
+
+    #[test]
+    fn test_wrong_placement() {
+        let src = "A = 1\nD = A\nJMP\nORG 1\nD = A\nJMP\nORG 0x7fff * 3\n.section 3\n.data\nA = 2";
+        let layout = SectionLayout {
+            bases: vec![("data".to_string(), 0)],
+            fill: 0,
+        };
+
+        let tokens: Vec<(Result<Token, LexError>, std::ops::Range<usize>)> = Token::lexer(src).spanned().collect();
+        let parser_report = parse(&tokens, &mut Loader::default(), &mut Targets::default(), &[], &layout, false);
+        let messages: Vec<String> = parser_report.report.iter().map(|e| e.to_string()).collect();
+        assert_eq!(
+            vec![
+                "Error: Value out of range",
+                "Error: Expected section name",
+                "Error: Two words are placed at address 0",
+            ],
+            messages
+        );
+    }
+
+    #[test]
+    fn test_address_space() {
+        let src = "ORG 0xffff\n.word 1";
+        let tokens: Vec<(Result<Token, LexError>, std::ops::Range<usize>)> = Token::lexer(src).spanned().collect();
+        let parser_report = parse(&tokens, &mut Loader::default(), &mut Targets::default(), &[], &SectionLayout::default(), false);
+        assert!(parser_report.report.is_empty());
+        assert_eq!(Instruction::Data(1), parser_report.instructions[0xffff]);
+
+        // a single report at the statement crossing the end
+        let layout = SectionLayout {
+            bases: vec![("top".to_string(), 0xfffe)],
+            fill: 0,
+        };
+        for src in ["ORG 0xffff\n.word 1, 2\n.word 3", ".section top\n.fill 3, 0\n.zero 1", "ORG 0xfffe\nA = 1\nD = A\nJMP"] {
+            let tokens: Vec<(Result<Token, LexError>, std::ops::Range<usize>)> = Token::lexer(src).spanned().collect();
+            let parser_report = parse(&tokens, &mut Loader::default(), &mut Targets::default(), &[], &layout, false);
+            let messages: Vec<String> = parser_report.report.iter().map(|e| e.to_string()).collect();
+            assert_eq!(vec!["Error: Address out of range"], messages);
+        }
+    }
+
+    #[test]
+    fn test_entry() {
+        let cases = [
+            ("A = 1\nmain: D = A\nA = main\nJMP", "A = 3\nJMP\nA = 1\nD = A\nA = 3\nJMP"),
+            (".entry start\nA = 1\nstart: JMP", "A = 3\nJMP\nA = 1\nJMP"),
+            // already the first instruction
+            (".entry start\nDEFINE one 1\nstart: A = one", "A = 1"),
+            ("main: A = 1\nJMP", "A = 1\nJMP"),
+        ];
+
+        for (src, placed) in cases {
+            let parser_report = assemble(src);
+            assert!(parser_report.report.is_empty(), "{src}");
+
+            assert_same_words(src, placed);
+        }
+    }
+
+    #[test]
+    fn test_wrong_entry() {
+        let src = ".entry nowhere\n.entry again\nJMP";
+
+        let tokens: Vec<(Result<Token, LexError>, std::ops::Range<usize>)> = Token::lexer(src).spanned().collect();
+        let parser_report = parse(&tokens, &mut Loader::default(), &mut Targets::default(), &[], &SectionLayout::default(), false);
+        let messages: Vec<String> = parser_report.report.iter().map(|e| e.to_string()).collect();
+        assert_eq!(
+            vec!["Error: Entry point already given", "Error: No label nowhere to start from"],
+            messages
+        );
+        assert_eq!(Some(Severity::Warning), parser_report.report[1].severity());
+
+        // the layout leaves no room for the reset stub
+        let src = "JMP\nmain: JMP";
+        let layout = SectionLayout {
+            bases: vec![("text".to_string(), 0)],
+            fill: 0,
+        };
+
+        let tokens: Vec<(Result<Token, LexError>, std::ops::Range<usize>)> = Token::lexer(src).spanned().collect();
+        let parser_report = parse(&tokens, &mut Loader::default(), &mut Targets::default(), &[], &layout, false);
+        let messages: Vec<String> = parser_report.report.iter().map(|e| e.to_string()).collect();
+        assert_eq!(vec!["Error: Two words are placed at address 0"], messages);
+    }
+
+    #[test]
+    fn test_identifiers() {
+        let src = "DEFINE motor.left 0x10\nDEFINE B motor.left + 1\nloop1: A = B\nA = loop1\nJMP";
+
+        let parser_report = assemble(src);
+        assert!(parser_report.report.is_empty());
+        assert_eq!(
+            "1000000000010001\n1000000000000000\n0111000000000000",
+            bit_stream(parser_report)
+        );
+    }
 
-        assert!(!parse(&mut tokens, false, false, "")
+    #[test]
+    fn test_local_labels() {
+        let src = "first: A = .done\nJMP\n.done: D = A\nsecond: A = .done\nJMP\n.done: A = first.done\nJMP";
+        let placed = "A = 2\nJMP\nD = A\nA = 5\nJMP\nA = 2\nJMP";
+
+        let parser_report = assemble(src);
+        let id_collect = &parser_report.id_collect;
+        assert_eq!(2, id_collect["first.done"].val);
+        assert_eq!(5, id_collect["second.done"].val);
+        // first and second aren't read
+        assert_eq!(2, parser_report.report.len());
+
+        assert_same_words(src, placed);
+    }
+
+    #[test]
+    fn test_numeric_labels() {
+        let src = "1: A = 1f\nD >\n1: A = 1b\nJMP\nREPT 2\n1: A = 1b\nJMP\nENDR\nA = 2f\nJMP\n2: D = A";
+        let placed = "A = 2\nD >\nA = 2\nJMP\nA = 4\nJMP\nA = 6\nJMP\nA = 10\nJMP\nD = A";
+
+        let parser_report = assemble(src);
+        // numeric labels are never reported as unused
+        assert!(parser_report.report.is_empty());
+
+        assert_same_words(src, placed);
+    }
+
+    #[test]
+    fn test_wrong_numeric_labels() {
+        let src = "A = 1b\n2: A = 3f\nJMP\nA = 2f\nJMP";
+
+        let parser_report = assemble(src);
+        let messages: Vec<String> = parser_report.report.iter().map(|e| e.to_string()).collect();
+        assert_eq!(
+            vec![
+                "Error: No label 1: before this reference",
+                "Error: No label 3: after this reference",
+                "Error: No label 2: after this reference",
+            ],
+            messages
+        );
+    }
+
+    #[test]
+    fn test_reserved_names() {
+        let src = "A: JMP\nDEFINE JMP 1\nDEFINE D 2\nSET DEFINE 1\nJMP:\nFOR Z = 0 TO 1\nENDF\nMACRO m *A\nENDM";
+
+        let parser_report = assemble(src);
+        let messages: Vec<String> = parser_report.report.iter().map(|e| e.to_string()).collect();
+        assert_eq!(
+            vec![
+                "Error: A is reserved and can't be used as a name",
+                "Error: JMP is reserved and can't be used as a name",
+                "Error: D is reserved and can't be used as a name",
+                "Error: DEFINE is reserved and can't be used as a name",
+                "Error: JMP is reserved and can't be used as a name",
+                "Error: Z is reserved and can't be used as a name",
+                "Error: *A is reserved and can't be used as a name",
+            ],
+            messages
+        );
+    }
+
+    #[test]
+    fn test_arch_directive() {
+        let src = ".arch v1\nJMP";
+
+        let tokens: Vec<(Result<Token, LexError>, std::ops::Range<usize>)> = Token::lexer(src).spanned().collect();
+        let parser_report = parse(&tokens, &mut Loader::default(), &mut Targets::default(), &[], &SectionLayout::default(), false);
+        assert!(parser_report.report.is_empty());
+        assert_eq!("0111000000000000", bit_stream(parser_report));
+
+        let src = "JMP\n.arch v1";
+
+        let tokens: Vec<(Result<Token, LexError>, std::ops::Range<usize>)> = Token::lexer(src).spanned().collect();
+        assert!(!parse(&tokens, &mut Loader::default(), &mut Targets::default(), &[], &SectionLayout::default(), false)
             .report
             .is_empty());
+
+        // only an assembled `.arch` selects its target
+        let isa = include_str!("../examples/arch_v1.toml")
+            .replace("name = \"v1_file\"", "name = \"no_v\"")
+            .replace("V = 0b010", "")
+            .replace("write = \"V\"", "write = \"D\"");
+        let cases = [
+            ("IFDEF NOPE\n.arch v2\nENDIF\nJMP", "v1", vec![]),
+            ("IF 0\n.arch no_v\nENDIF\nD = V", "v1", vec![]),
+            (".arch v2\nJMP", "v1", vec!["Error: Unknown architecture"]),
+            (".arch no_v\nD = V\nV = D\nD = D ^ V", "no_v", vec!["Error: Unknown register"; 3]),
+        ];
+        for (src, target, expected) in cases {
+            let mut targets = Targets::default();
+            targets.add(Box::new(isa::parse("no_v.toml", &isa).unwrap()));
+            let tokens: Vec<(Result<Token, LexError>, std::ops::Range<usize>)> = Token::lexer(src).spanned().collect();
+            let parser_report = parse(&tokens, &mut Loader::default(), &mut targets, &[], &SectionLayout::default(), false);
+            let messages: Vec<String> = parser_report.report.iter().map(|e| e.to_string()).collect();
+            assert_eq!(expected, messages);
+            assert_eq!(target, targets.active().name());
+        }
+    }
+
+    #[test]
+    fn test_arch_names() {
+        let isa = include_str!("../examples/arch_v1.toml")
+            .replace("name = \"v1_file\"", "name = \"named\"")
+            .replace("V = 0b010", "B = 0b010")
+            .replace("\"*V\" = 0b011", "\"*B\" = 0b011")
+            .replace("\"^\" = 0b100", "XOR = 0b100\nSHL = 0b110")
+            .replace("\"!=\" = 0b101", "NZ = 0b101")
+            .replace("write = \"V\"", "write = \"B\"")
+            .replace("read = \"*V\"", "read = \"*B\"");
+        let assemble_named = |src: &str| {
+            let mut targets = Targets::default();
+            targets.add(Box::new(isa::parse("named.toml", &isa).unwrap()));
+            let tokens: Vec<(Result<Token, LexError>, std::ops::Range<usize>)> = Token::lexer(src).spanned().collect();
+            let parser_report = parse(&tokens, &mut Loader::default(), &mut targets, &[], &SectionLayout::default(), false);
+            let messages: Vec<String> = parser_report.report.iter().map(|e| e.to_string()).collect();
+            (render::bit_stream(&parser_report.instructions, targets.active(), "\n"), messages)
+        };
+
+        // the same codes as the v1 names they replace
+        let (words, messages) = assemble_named(".arch named\nB = A\n*B = B XOR D\nD NZ\nD = D SHL A");
+        assert!(messages.is_empty());
+        colored::control::set_override(false);
+        let v1 = bit_stream(assemble("V = A\n*V = V ^ D\nD !="));
+        assert_eq!(format!("{v1}\n0110000100000100"), words);
+
+        let cases = [
+            // a name of the architecture can't be a label
+            (".arch named\nB: JMP", vec!["Error: B is reserved and can't be used as a name"]),
+            (".arch named\nB = *B\nD = V", vec!["Error Can't change B value when reading *B", "Error: Unknown register"]),
+            // they are only names for v1
+            ("B: A = B\nJMP", vec![]),
+            ("D = D SHL A", vec!["Error: Expected end of line"]),
+        ];
+        for (src, expected) in cases {
+            assert_eq!(expected, assemble_named(src).1, "{src}");
+        }
     }
 }
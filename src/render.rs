@@ -0,0 +1,137 @@
+use crate::lexer::spec::{Architecture, Instruction};
+use crate::source::SourceMap;
+use colored::{Color, Colorize};
+use std::ops::Range;
+
+/// format the words of a program as a bit stream, coloring the fields
+/// of each word when colors are enabled
+pub fn bit_stream(instructions: &[Instruction], arch: &dyn Architecture, sep: &str) -> String {
+    instructions
+        .iter()
+        .map(|inst| word(inst, arch))
+        .collect::<Vec<String>>()
+        .join(sep)
+}
+
+/// format a listing of a program, the address and the word of each instruction
+/// are followed by its source and the notes on the instructions it starts
+pub fn listing(
+    instructions: &[Instruction],
+    spans: &[Range<usize>],
+    notes: &[(usize, String)],
+    arch: &dyn Architecture,
+    sources: &SourceMap,
+) -> String {
+    let per_word = arch.address_unit().per_word() as usize;
+    let mut lines = vec![];
+    for (index, inst) in instructions.iter().enumerate() {
+        let source = spans.get(index).map_or("", |span| sources.text(span));
+        let mut line = format!(
+            "{:04x}  {:016b}  {}",
+            index * per_word,
+            inst.encode(arch),
+            source.lines().next().unwrap_or_default()
+        );
+        for (_, note) in notes.iter().filter(|(first, _)| *first == index) {
+            line += &format!("  ; {note}");
+        }
+        lines.push(line.trim_end().to_string());
+    }
+    lines.join("\n")
+}
+
+/// format the word of an instruction, coloring each of its field
+pub fn word(inst: &Instruction, arch: &dyn Architecture) -> String {
+    let layout = arch.layout();
+    let bits = inst.encode(arch);
+    // a data word has no fields
+    if let Instruction::Data(_) = inst {
+        return format!("{bits:016b}").white().to_string();
+    }
+
+    let data_mode = matches!(inst, Instruction::Load(_));
+    let fields = match data_mode {
+        true => vec![(layout.mode, Color::Green), (layout.value, Color::Red)],
+        false => vec![
+            (layout.mode, Color::Green),
+            (layout.code, Color::Blue),
+            (layout.src_a, Color::Yellow),
+            (layout.src_b, Color::Magenta),
+            (layout.dest, Color::Cyan),
+        ],
+    };
+    let field_of = |bit: u16| fields.iter().position(|(field, _)| field.contains(bit));
+
+    let mut formatted = String::new();
+    let mut chunk = String::new();
+    for bit in (0..16).rev() {
+        chunk.push(if bits & (1 << bit) == 0 { '0' } else { '1' });
+        if bit > 0 && field_of(bit) == field_of(bit - 1) {
+            continue;
+        }
+        let colored = match field_of(bit) {
+            Some(0) if !data_mode => chunk.green().bold(),
+            Some(index) => chunk.color(fields[index].1),
+            None => chunk.normal(),
+        };
+        formatted += &colored.to_string();
+        chunk = String::new();
+    }
+    formatted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::spec::arch_v1::ArchV1;
+    use crate::lexer::spec::{Cond, Op, Reg, Targets};
+    use crate::parser::parse;
+    use crate::sections::SectionLayout;
+    use crate::source::Loader;
+
+    #[test]
+    fn test_bit_stream() {
+        colored::control::set_override(false);
+
+        let instructions = [
+            Instruction::Load(42),
+            Instruction::Alu {
+                op: Op::And,
+                a: Reg::D,
+                b: Reg::AStar,
+                dest: Reg::D,
+            },
+            Instruction::Condition {
+                cond: Cond::Neq,
+                reg: Reg::D,
+            },
+            Instruction::Jump,
+            Instruction::Data(0xbeef),
+        ];
+        let expected = "1000000000101010 0010000100001100 0101000100110110 0111000000000000 1011111011101111";
+
+        assert_eq!(expected, bit_stream(&instructions, &ArchV1, " "));
+    }
+
+    #[test]
+    fn test_listing() {
+        let mut loader = Loader::default();
+        let tokens = loader.load("test.asm", "A = -3\nJMP\nORG 4\n.word 1".to_string());
+        let parser_report = parse(&tokens, &mut loader, &mut Targets::default(), &[], &SectionLayout::default(), false);
+        let expected = "\
+0000  1000000000000010  A = -3  ; -3 doesn't fit a data mode word, it is loaded by 2 instructions
+0001  0101000000000000  A = -3
+0002  0111000000000000  JMP
+0003  0000000000000000
+0004  0000000000000001  1";
+
+        let listing = listing(
+            &parser_report.instructions,
+            &parser_report.spans,
+            &parser_report.notes,
+            &ArchV1,
+            &loader.sources,
+        );
+        assert_eq!(expected, listing);
+    }
+}
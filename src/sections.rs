@@ -0,0 +1,98 @@
+use miette::{miette, Error, LabeledSpan, NamedSource};
+use serde::Deserialize;
+use std::collections::HashMap;
+use toml::Spanned;
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Description {
+    #[serde(default)]
+    fill: u16,
+    #[serde(default)]
+    sections: HashMap<Spanned<String>, u16>,
+}
+
+/// Base address of the sections, a section left out starts
+/// after the words placed so far
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct SectionLayout {
+    pub bases: Vec<(String, u16)>,
+    /// word placed in the gaps between the sections
+    pub fill: u16,
+}
+
+impl SectionLayout {
+    pub fn base(&self, name: &str) -> Option<u16> {
+        self.bases
+            .iter()
+            .find(|(known, _)| known == name)
+            .map(|(_, base)| *base)
+    }
+}
+
+/// read the section layout file located at `path`
+pub fn load(path: &str) -> Result<SectionLayout, Error> {
+    match std::fs::read_to_string(path) {
+        Ok(content) => parse(path, &content),
+        Err(e) => Err(miette!("Error: Unable to read layout file {path}: {e}")),
+    }
+}
+
+/// check a section layout, `path` is only used in the reports
+pub fn parse(path: &str, content: &str) -> Result<SectionLayout, Error> {
+    let with_source = |e: Error| e.with_source_code(NamedSource::new(path, content.to_string()));
+
+    let description: Description = toml::from_str(content).map_err(|e| {
+        with_source(miette!(
+            labels = vec![LabeledSpan::at(e.span().unwrap_or(0..0), "Here")],
+            "Error: {}",
+            e.message()
+        ))
+    })?;
+
+    let mut bases: Vec<(Spanned<String>, u16)> = description.sections.into_iter().collect();
+    // keep the order of the file
+    bases.sort_by_key(|(name, _)| name.span().start);
+    for (name, _) in &bases {
+        let valid = name
+            .get_ref()
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if !valid {
+            return Err(with_source(miette!(
+                labels = vec![LabeledSpan::at(name.span(), "Here")],
+                "Error: {} isn't a valid section name",
+                name.get_ref()
+            )));
+        }
+    }
+
+    Ok(SectionLayout {
+        bases: bases
+            .into_iter()
+            .map(|(name, base)| (name.into_inner(), base))
+            .collect(),
+        fill: description.fill,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_layout_file() {
+        let src = "fill = 0x7000\n\n[sections]\nreset = 0\ntext = 0x10\ndata = 0x4000\n";
+
+        let layout = parse("layout.toml", src).unwrap();
+        assert_eq!(0x7000, layout.fill);
+        assert_eq!(Some(0), layout.base("reset"));
+        assert_eq!(Some(0x4000), layout.base("data"));
+        assert_eq!(None, layout.base("vectors"));
+        assert_eq!("reset", layout.bases[0].0);
+
+        assert!(parse("wrong.toml", "[sections]\ntext = -1").is_err());
+        assert!(parse("wrong.toml", "[sections]\n\"te xt\" = 0").is_err());
+        assert!(parse("wrong.toml", "base = 0").is_err());
+    }
+}
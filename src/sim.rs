@@ -0,0 +1,316 @@
+use crate::lexer::spec::{Architecture, Cond, Instruction, Op, Reg};
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// Why the simulation stopped
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Stop {
+    /// the program counter went past the last instruction
+    End,
+    MaxCycles,
+    /// an operation or condition declared by an ISA file, its effect isn't known
+    Unknown,
+}
+
+impl fmt::Display for Stop {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Stop::End => write!(f, "end of program"),
+            Stop::MaxCycles => write!(f, "maximum number of cycles reached"),
+            Stop::Unknown => write!(f, "instruction unknown to the simulator"),
+        }
+    }
+}
+
+/// Instruction set simulator of the cpu
+///
+/// - a data mode word loads its value into the load register
+/// - `*A` and `*V` read and write the memory at the address held by A and V
+/// - Z and O always read 0 and 1, writing them has no effect
+/// - a condition compares its register (as a signed value) to 0 and skips the
+///   next instruction when it doesn't hold
+/// - `JMP` continues the execution at the address held by A
+/// - a data word is executed as the instruction it encodes, if any
+/// - the registers declared by an ISA file hold a value like D, `*B` is the
+///   memory at the address held by B, its operations and conditions stop
+///   the simulation
+pub struct Machine<'a> {
+    arch: &'a dyn Architecture,
+    program: Vec<Instruction>,
+    pub a: u16,
+    pub v: u16,
+    pub d: u16,
+    /// registers declared by an ISA file, by name
+    pub registers: BTreeMap<String, u16>,
+    /// index of the next instruction
+    pub pc: usize,
+    pub cycles: usize,
+    /// every memory address read or written so far
+    pub memory: BTreeMap<u16, u16>,
+    /// memory address written by the last instruction
+    pub last_write: Option<u16>,
+}
+
+impl<'a> Machine<'a> {
+    pub fn new(program: Vec<Instruction>, arch: &'a dyn Architecture) -> Self {
+        Machine {
+            arch,
+            program,
+            a: 0,
+            v: 0,
+            d: 0,
+            registers: BTreeMap::new(),
+            pc: 0,
+            cycles: 0,
+            memory: BTreeMap::new(),
+            last_write: None,
+        }
+    }
+
+    fn read(&mut self, reg: Reg) -> u16 {
+        match reg {
+            Reg::A => self.a,
+            Reg::V => self.v,
+            Reg::D => self.d,
+            Reg::AStar => *self.memory.entry(self.a).or_insert(0),
+            Reg::VStar => *self.memory.entry(self.v).or_insert(0),
+            Reg::Zero => 0,
+            Reg::One => 1,
+            Reg::Named(name) => match name.as_str().strip_prefix('*') {
+                Some(pointer) => {
+                    let adr = self.named(pointer);
+                    *self.memory.entry(adr).or_insert(0)
+                }
+                None => self.named(name.as_str()),
+            },
+        }
+    }
+
+    /// value of a register declared by an ISA file
+    fn named(&self, name: &str) -> u16 {
+        self.registers.get(name).copied().unwrap_or_default()
+    }
+
+    fn write(&mut self, reg: Reg, val: u16) {
+        match reg {
+            Reg::A => self.a = val,
+            Reg::V => self.v = val,
+            Reg::D => self.d = val,
+            Reg::AStar => {
+                self.memory.insert(self.a, val);
+                self.last_write = Some(self.a);
+            }
+            Reg::VStar => {
+                self.memory.insert(self.v, val);
+                self.last_write = Some(self.v);
+            }
+            Reg::Zero | Reg::One => (),
+            Reg::Named(name) => match name.as_str().strip_prefix('*') {
+                Some(pointer) => {
+                    let adr = self.named(pointer);
+                    self.memory.insert(adr, val);
+                    self.last_write = Some(adr);
+                }
+                None => {
+                    self.registers.insert(name.to_string(), val);
+                }
+            },
+        }
+    }
+
+    /// whether the effect of an instruction is known
+    fn known(&self, inst: Instruction) -> bool {
+        match inst {
+            Instruction::Alu { op: Op::Named(_), .. } => false,
+            Instruction::Condition { cond: Cond::Named(_), .. } => false,
+            Instruction::Data(word) => self.arch.decode(word).map_or(true, |decoded| self.known(decoded)),
+            _ => true,
+        }
+    }
+
+    /// index of the instruction at an address
+    pub fn index_of(&self, adr: u16) -> usize {
+        (adr / self.arch.address_unit().per_word()) as usize
+    }
+
+    pub fn is_over(&self) -> bool {
+        self.pc >= self.program.len()
+    }
+
+    /// execute the next instruction, returns None once the program is over
+    /// or when the effect of the instruction isn't known
+    pub fn step(&mut self) -> Option<Instruction> {
+        let inst = *self.program.get(self.pc)?;
+        if !self.known(inst) {
+            return None;
+        }
+        self.pc += 1;
+        self.cycles += 1;
+        self.last_write = None;
+        self.execute(inst);
+
+        Some(inst)
+    }
+
+    fn execute(&mut self, inst: Instruction) {
+        match inst {
+            Instruction::Load(value) => self.write(self.arch.load_register(), value),
+            Instruction::Alu { op, a, b, dest } => {
+                let (a, b) = (self.read(a), self.read(b));
+                let result = match op {
+                    Op::Add => a.wrapping_add(b),
+                    Op::Sub => a.wrapping_sub(b),
+                    Op::And => a & b,
+                    Op::Or => a | b,
+                    Op::Xor => a ^ b,
+                    Op::Not => !a,
+                    // not executed, see `known`
+                    Op::Named(_) => return,
+                };
+                self.write(dest, result);
+            }
+            Instruction::Condition { cond, reg } => {
+                let val = self.read(reg) as i16;
+                let holds = match cond {
+                    Cond::Eq => val == 0,
+                    Cond::Neq => val != 0,
+                    Cond::Gt => val > 0,
+                    Cond::Lt => val < 0,
+                    Cond::GtEq => val >= 0,
+                    Cond::LtEq => val <= 0,
+                    Cond::Jump => true,
+                    Cond::Named(_) => return,
+                };
+                if !holds {
+                    self.pc += 1;
+                }
+            }
+            Instruction::Jump => self.pc = self.index_of(self.a),
+            // the cpu doesn't know a data word from an instruction
+            Instruction::Data(word) => {
+                if let Ok(decoded) = self.arch.decode(word) {
+                    self.execute(decoded);
+                }
+            }
+        }
+    }
+
+    /// execute the program until it ends or `max_cycles` instructions were executed
+    pub fn run(&mut self, max_cycles: usize) -> Stop {
+        while self.cycles < max_cycles {
+            if self.step().is_none() {
+                return match self.is_over() {
+                    true => Stop::End,
+                    false => Stop::Unknown,
+                };
+            }
+        }
+        match self.is_over() {
+            true => Stop::End,
+            false => Stop::MaxCycles,
+        }
+    }
+}
+
+impl fmt::Display for Machine<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "A = 0x{:04x}  V = 0x{:04x}  D = 0x{:04x}",
+            self.a, self.v, self.d
+        )?;
+        for (name, val) in &self.registers {
+            writeln!(f, "{name} = 0x{val:04x}")?;
+        }
+        writeln!(f, "memory:")?;
+        for (adr, val) in &self.memory {
+            writeln!(f, "  0x{adr:04x} = 0x{val:04x}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::spec::{arch_v1::ArchV1, isa, Targets};
+    use crate::lexer::{LexError, Token};
+    use crate::parser::parse;
+    use crate::sections::SectionLayout;
+    use crate::source::Loader;
+    use logos::Logos;
+    use miette::Severity;
+
+    fn machine(src: &str) -> Machine<'static> {
+        let lex = Token::lexer(src);
+        let tokens: Vec<(Result<Token, LexError>, std::ops::Range<usize>)> = lex.spanned().collect();
+        let parser_report = parse(&tokens, &mut Loader::default(), &mut Targets::default(), &[], &SectionLayout::default(), false);
+        assert!(parser_report
+            .report
+            .iter()
+            .all(|r| r.severity() == Some(Severity::Warning)));
+        Machine::new(parser_report.instructions, &ArchV1)
+    }
+
+    #[test]
+    fn test_alu() {
+        let mut machine = machine("A = 5\nD = A\nA = 3\nD = D - A\nV = ~D\nA = 42\n*A = D + A");
+        assert_eq!(Stop::End, machine.run(100));
+        assert_eq!(42, machine.a);
+        assert_eq!(2, machine.d);
+        assert_eq!(!2, machine.v);
+        assert_eq!(Some(&44), machine.memory.get(&42));
+        assert_eq!(7, machine.cycles);
+    }
+
+    #[test]
+    fn test_condition() {
+        // D is 0 so the first jump is skipped and the second one is taken
+        let src = "D = A\nA = end\nD !=\nJMP\nD ==\nJMP\nA = 1\nend:\nV = A";
+        let mut machine = machine(src);
+        assert_eq!(Stop::End, machine.run(100));
+        assert_eq!(machine.v, 7);
+        assert_eq!(6, machine.cycles);
+    }
+
+    #[test]
+    fn test_max_cycles() {
+        let mut machine = machine("loop:\nA = loop\nJMP");
+        assert_eq!(Stop::MaxCycles, machine.run(10));
+        assert_eq!(10, machine.cycles);
+    }
+
+    #[test]
+    fn test_realistic_program() {
+        let src = std::fs::read_to_string("tests/real_test/realistic_test.asm").unwrap();
+        let mut machine = machine(&src);
+        // obstacle detected, the robot must turn left
+        machine.memory.insert(0x7fff, 0x100);
+        machine.run(18);
+        assert_eq!(Some(&0x8), machine.memory.get(&0x7fff));
+    }
+
+    #[test]
+    fn test_named() {
+        let isa = include_str!("../examples/arch_v1.toml")
+            .replace("name = \"v1_file\"", "name = \"named\"")
+            .replace("\"^\" = 0b100", "SHL = 0b100")
+            .replace("V = 0b010", "B = 0b010")
+            .replace("\"*V\" = 0b011", "\"*B\" = 0b011")
+            .replace("write = \"V\"", "write = \"B\"")
+            .replace("read = \"*V\"", "read = \"*B\"");
+        let mut targets = Targets::default();
+        targets.add(Box::new(isa::parse("named.toml", &isa).unwrap()));
+        let src = ".arch named\nA = 5\nB = A\n*B = B + 1\nD = D SHL A\nD = A";
+        let tokens: Vec<(Result<Token, LexError>, std::ops::Range<usize>)> = Token::lexer(src).spanned().collect();
+        let parser_report = parse(&tokens, &mut Loader::default(), &mut targets, &[], &SectionLayout::default(), false);
+        assert!(parser_report.report.is_empty());
+
+        // the operation of the ISA file isn't executed
+        let mut machine = Machine::new(parser_report.instructions, targets.active());
+        assert_eq!(Stop::Unknown, machine.run(100));
+        assert_eq!(Some(&5), machine.registers.get("B"));
+        assert_eq!(Some(&6), machine.memory.get(&5));
+        assert_eq!(3, machine.pc);
+    }
+}
@@ -0,0 +1,400 @@
+use crate::lexer::Token;
+use crate::macros::SpannedToken;
+use logos::Logos;
+use miette::{
+    miette, Diagnostic, Error, LabeledSpan, MietteError, MietteSpanContents, NamedSource, Severity,
+    SourceCode, SourceSpan, SpanContents,
+};
+use std::fmt;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+/// File of a program, the spans of its tokens start at `base`
+#[derive(Clone)]
+struct SourceFile {
+    source: NamedSource<String>,
+    /// canonical path, a file is included once
+    identity: PathBuf,
+    base: usize,
+    /// INCLUDE directive which loaded it
+    included_from: Option<Range<usize>>,
+}
+
+impl SourceFile {
+    fn len(&self) -> usize {
+        self.source.inner().len()
+    }
+}
+
+/// Files of a program laid one after the other, so that a span
+/// designates a single place in one of them
+#[derive(Clone, Default)]
+pub struct SourceMap {
+    files: Vec<SourceFile>,
+}
+
+impl SourceMap {
+    /// add a file and returns the offset of its first byte
+    fn add(
+        &mut self,
+        name: &str,
+        content: String,
+        identity: PathBuf,
+        included_from: Option<Range<usize>>,
+    ) -> usize {
+        // a gap keeps the end of a file apart from the start of the next one
+        let base = self
+            .files
+            .last()
+            .map_or(0, |file| file.base + file.len() + 1);
+        self.files.push(SourceFile {
+            source: NamedSource::new(name, content),
+            identity,
+            base,
+            included_from,
+        });
+        base
+    }
+
+    fn file_of(&self, offset: usize) -> Option<&SourceFile> {
+        self.files
+            .iter()
+            .rev()
+            .find(|file| file.base <= offset && offset <= file.base + file.len())
+    }
+
+    /// name of the file holding an offset and the number of its line
+    pub fn line_of(&self, offset: usize) -> (&str, usize) {
+        match self.file_of(offset) {
+            Some(file) => {
+                let text = &file.source.inner()[..offset - file.base];
+                (file.source.name(), text.matches('\n').count() + 1)
+            }
+            None => ("", 0),
+        }
+    }
+
+    /// source text of a span, empty when it isn't in a file
+    pub fn text(&self, span: &Range<usize>) -> &str {
+        self.file_of(span.start)
+            .and_then(|file| file.source.inner().get(span.start - file.base..span.end - file.base))
+            .unwrap_or_default()
+    }
+
+    /// name of the file which was assembled
+    pub fn main(&self) -> &str {
+        self.files.first().map_or("", |file| file.source.name())
+    }
+
+    /// label the INCLUDE directives which loaded the file of an error
+    pub fn included_from(&self, error: Error) -> Error {
+        let Some(first) = error.labels().and_then(|mut labels| labels.next()) else {
+            return error;
+        };
+
+        let mut labels = vec![];
+        let mut file = self.file_of(first.offset());
+        while let Some(span) = file.and_then(|file| file.included_from.clone()) {
+            file = self.file_of(span.start);
+            labels.push(LabeledSpan::at(span, "included from here"));
+        }
+        with_labels(error, labels)
+    }
+}
+
+impl SourceCode for SourceMap {
+    fn read_span<'a>(
+        &'a self,
+        span: &SourceSpan,
+        context_lines_before: usize,
+        context_lines_after: usize,
+    ) -> Result<Box<dyn SpanContents<'a> + 'a>, MietteError> {
+        let file = self
+            .file_of(span.offset())
+            .ok_or(MietteError::OutOfBounds)?;
+        if span.offset() + span.len() > file.base + file.len() {
+            return Err(MietteError::OutOfBounds);
+        }
+
+        let local = SourceSpan::new((span.offset() - file.base).into(), span.len());
+        let contents = file
+            .source
+            .read_span(&local, context_lines_before, context_lines_after)?;
+        let span = SourceSpan::new(
+            (contents.span().offset() + file.base).into(),
+            contents.span().len(),
+        );
+        Ok(Box::new(MietteSpanContents::new_named(
+            file.source.name().to_string(),
+            contents.data(),
+            span,
+            contents.line(),
+            contents.column(),
+            contents.line_count(),
+        )))
+    }
+}
+
+/// Report with more labels than the error it wraps
+#[derive(Debug)]
+struct Labelled {
+    error: Error,
+    labels: Vec<LabeledSpan>,
+}
+
+impl fmt::Display for Labelled {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.error)
+    }
+}
+
+impl std::error::Error for Labelled {}
+
+impl Diagnostic for Labelled {
+    fn severity(&self) -> Option<Severity> {
+        self.error.severity()
+    }
+
+    fn help<'a>(&'a self) -> Option<Box<dyn fmt::Display + 'a>> {
+        self.error.help()
+    }
+
+    fn labels(&self) -> Option<Box<dyn Iterator<Item = LabeledSpan> + '_>> {
+        let labels = self.error.labels().into_iter().flatten();
+        Some(Box::new(labels.chain(self.labels.iter().cloned())))
+    }
+}
+
+/// add labels to an error
+pub fn with_labels(error: Error, labels: Vec<LabeledSpan>) -> Error {
+    match labels.is_empty() {
+        true => error,
+        false => Error::new(Labelled { error, labels }),
+    }
+}
+
+/// Lexer of a program and of the files it includes, an INCLUDE directive
+/// is replaced by the tokens of its file when the parser assembles it
+#[derive(Default)]
+pub struct Loader {
+    /// directories searched after the one of the including file
+    search_paths: Vec<String>,
+    pub sources: SourceMap,
+    /// every file already loaded, it isn't included again
+    loaded: Vec<PathBuf>,
+}
+
+impl Loader {
+    pub fn new(search_paths: &[String]) -> Self {
+        Loader {
+            search_paths: search_paths.to_vec(),
+            ..Default::default()
+        }
+    }
+
+    /// tokens of the assembled file
+    pub fn load(&mut self, path: &str, content: String) -> Vec<SpannedToken> {
+        self.lex(Path::new(path), content, None)
+    }
+
+    fn lex(
+        &mut self,
+        path: &Path,
+        content: String,
+        included_from: Option<Range<usize>>,
+    ) -> Vec<SpannedToken> {
+        let identity = path.canonicalize().unwrap_or(path.to_path_buf());
+        let base = self
+            .sources
+            .add(&path.to_string_lossy(), content.clone(), identity.clone(), included_from);
+        self.loaded.push(identity);
+
+        Token::lexer(&content)
+            .spanned()
+            .map(|(token, span)| (token, span.start + base..span.end + base))
+            .collect()
+    }
+
+    /// tokens of a file included by the `directive` of a loaded file,
+    /// none if it was already loaded
+    pub fn include(
+        &mut self,
+        name: &str,
+        span: Range<usize>,
+        directive: Range<usize>,
+    ) -> Result<Vec<SpannedToken>, Error> {
+        // files including the directive, the innermost last
+        let mut including = vec![];
+        let mut file = self.sources.file_of(directive.start);
+        while let Some(known) = file {
+            including.insert(0, known.identity.clone());
+            file = (known.included_from.as_ref()).and_then(|span| self.sources.file_of(span.start));
+        }
+
+        let directories = (including.last())
+            .and_then(|from| from.parent())
+            .map(|dir| dir.to_path_buf())
+            .into_iter()
+            .chain(self.search_paths.iter().map(PathBuf::from));
+        let found = directories
+            .map(|dir| dir.join(name))
+            .find(|path| path.is_file());
+
+        let Some(path) = found else {
+            return Err(miette!(
+                labels = vec![LabeledSpan::at(span, "not found")],
+                help = "Add the directory holding it with -I",
+                "Error: Unable to find {name}"
+            ));
+        };
+
+        let identity = path.canonicalize().unwrap_or(path.clone());
+        if let Some(start) = including.iter().position(|file| *file == identity) {
+            let mut cycle: Vec<String> = including[start..]
+                .iter()
+                .map(|file| file.to_string_lossy().to_string())
+                .collect();
+            cycle.push(identity.to_string_lossy().to_string());
+            return Err(miette!(
+                labels = vec![LabeledSpan::at(directive, "Here")],
+                "Error: Circular inclusion {}",
+                cycle.join(" -> ")
+            ));
+        }
+        if self.loaded.contains(&identity) {
+            return Ok(vec![]);
+        }
+
+        match std::fs::read_to_string(&path) {
+            Ok(content) => Ok(self.lex(&path, content, Some(directive))),
+            Err(e) => Err(miette!(
+                labels = vec![LabeledSpan::at(span, "Here")],
+                "Error: Unable to read {name}: {e}"
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::spec::{Instruction, Targets};
+    use crate::parser::{parse, ParserReport};
+    use crate::sections::SectionLayout;
+
+    /// directory holding `files`, named after the test
+    fn write_files(test: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("simple_assembler_{test}"));
+        for (name, content) in files {
+            let path = dir.join(name);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, content).unwrap();
+        }
+        dir
+    }
+
+    /// parse the `main.asm` file of a directory
+    fn assemble(dir: &Path, search_paths: &[String]) -> (ParserReport, Loader) {
+        let main = dir.join("main.asm");
+        let content = std::fs::read_to_string(&main).unwrap();
+        let mut loader = Loader::new(search_paths);
+        let tokens = loader.load(&main.to_string_lossy(), content);
+        let layout = SectionLayout::default();
+        let parser_report = parse(&tokens, &mut loader, &mut Targets::default(), &[], &layout, false);
+        (parser_report, loader)
+    }
+
+    fn messages(errors: &[Error]) -> Vec<String> {
+        errors.iter().map(|e| e.to_string()).collect()
+    }
+
+    #[test]
+    fn test_include() {
+        let dir = write_files(
+            "include",
+            &[
+                (
+                    "main.asm",
+                    "INCLUDE \"io.asm\"\nINCLUDE \"io.asm\" ; once\nA = io",
+                ),
+                ("lib/io.asm", "DEFINE io 0x7fff\n"),
+            ],
+        );
+        let search = [dir.join("lib").to_string_lossy().to_string()];
+
+        let (parser_report, loader) = assemble(&dir, &search);
+        assert!(parser_report.report.is_empty());
+        assert_eq!(vec![Instruction::Load(0x7fff)], parser_report.instructions);
+
+        // DEFINE io is in the included file
+        let span = parser_report.id_collect["io"].span.clone();
+        let (name, line) = loader.sources.line_of(span.start);
+        assert!(name.ends_with("io.asm"));
+        assert_eq!(1, line);
+        let (name, line) = loader.sources.line_of(parser_report.spans[0].start);
+        assert_eq!(loader.sources.main(), name);
+        assert_eq!(3, line);
+
+        let contents = loader.sources.read_span(&span.into(), 0, 0).unwrap();
+        assert_eq!(b"io", contents.data());
+    }
+
+    #[test]
+    fn test_conditional_include() {
+        // a file is only looked for when its INCLUDE is assembled,
+        // and it is included once the first time it is
+        let dir = write_files(
+            "conditional_include",
+            &[
+                (
+                    "main.asm",
+                    "IFDEF NOPE\nINCLUDE \"missing.asm\"\nENDIF\nIF 0\nINCLUDE \"a.asm\"\nENDIF\nINCLUDE \"a.asm\"\nIFDEF a\nA = a\nENDIF",
+                ),
+                ("a.asm", "DEFINE a 1"),
+            ],
+        );
+
+        let (parser_report, _) = assemble(&dir, &[]);
+        assert!(parser_report.report.is_empty());
+        assert_eq!(vec![Instruction::Load(1)], parser_report.instructions);
+    }
+
+    #[test]
+    fn test_wrong_include() {
+        let dir = write_files(
+            "wrong_include",
+            &[
+                (
+                    "main.asm",
+                    "INCLUDE \"a.asm\"\nINCLUDE \"missing.asm\"\nINCLUDE a.asm\nINCLUDE",
+                ),
+                ("a.asm", "INCLUDE \"b.asm\""),
+                ("b.asm", "INCLUDE \"a.asm\""),
+            ],
+        );
+
+        let (parser_report, loader) = assemble(&dir, &[]);
+        let a = dir.join("a.asm").canonicalize().unwrap();
+        let b = dir.join("b.asm").canonicalize().unwrap();
+        assert_eq!(
+            vec![
+                format!(
+                    "Error: Circular inclusion {} -> {} -> {}",
+                    a.display(),
+                    b.display(),
+                    a.display()
+                ),
+                "Error: Unable to find missing.asm".to_string(),
+                "Error: Expected file path".to_string(),
+                "Error: Expected file path".to_string(),
+            ],
+            messages(&parser_report.report)
+        );
+
+        // the cycle is reported in b.asm, included from main.asm then a.asm
+        let error = loader
+            .sources
+            .included_from(parser_report.report.into_iter().next().unwrap());
+        assert_eq!(3, error.labels().unwrap().count());
+    }
+}
@@ -1,167 +1,1148 @@
-pub mod arch_v1 {
-    use crate::lexer::HandleToken;
-    use logos::Lexer;
-    use colored::Colorize;
+use crate::lexer::{HandleToken, LexError};
+use logos::Lexer;
+use serde::Deserialize;
+use std::fmt;
+
+/// Everything the assembler needs to know about a target cpu: registers and
+/// operations encodings, loadable values, word layout and legality rules
+pub trait Architecture {
+    /// name used to select the architecture (`--arch` or `.arch`)
+    fn name(&self) -> &str;
+
+    /// biggest value that can be loaded by a data mode word
+    fn max_load_value(&self) -> u16;
+
+    /// register written by a data mode word
+    fn load_register(&self) -> Reg;
+
+    /// position of the fields in a word
+    fn layout(&self) -> Layout;
+
+    /// what the addresses held by labels and used by `JMP` count
+    fn address_unit(&self) -> AddressUnit;
+
+    /// code of a register, None if the register doesn't exist on this cpu
+    fn reg_code(&self, reg: Reg) -> Option<u16>;
+
+    /// code of an operation, None if the operation doesn't exist on this cpu
+    fn op_code(&self, op: Op) -> Option<u16>;
+
+    /// code of a condition, None if the condition doesn't exist on this cpu
+    fn cond_code(&self, cond: Cond) -> Option<u16>;
+
+    /// whether `dest` can't be written while `src` is being read
+    fn conflicts(&self, dest: Reg, src: Reg) -> bool;
+
+    /// registers of this cpu
+    fn registers(&self) -> Vec<Reg> {
+        Reg::ALL.into_iter().filter(|reg| self.reg_code(*reg).is_some()).collect()
+    }
+
+    /// operations of this cpu
+    fn operations(&self) -> Vec<Op> {
+        Op::ALL.into_iter().filter(|op| self.op_code(*op).is_some()).collect()
+    }
+
+    /// conditions of this cpu
+    fn conditions(&self) -> Vec<Cond> {
+        Cond::ALL.into_iter().filter(|cond| self.cond_code(*cond).is_some()).collect()
+    }
+
+    /// turn a word back into the instruction it encodes
+    fn decode(&self, word: u16) -> Result<Instruction, DecodeError> {
+        let layout = self.layout();
+
+        if layout.mode.extract(word) == 1 {
+            let reserved = word & !(layout.mode.mask() | layout.value.mask());
+            if reserved != 0 {
+                return Err(DecodeError::ReservedBits(reserved));
+            }
+            return Ok(Instruction::Load(layout.value.extract(word)));
+        }
+
+        let used = layout.mode.mask()
+            | layout.code.mask()
+            | layout.src_a.mask()
+            | layout.src_b.mask()
+            | layout.dest.mask();
+        if word & !used != 0 {
+            return Err(DecodeError::ReservedBits(word & !used));
+        }
+
+        let reg = |field: Field, name: &'static str| {
+            let code = field.extract(word);
+            self.registers()
+                .into_iter()
+                .find(|reg| self.reg_code(*reg) == Some(code))
+                .ok_or(DecodeError::IllegalRegister { field: name, code })
+        };
+        let code = layout.code.extract(word);
+        let (a, b, dest) = (
+            reg(layout.src_a, "src_a")?,
+            reg(layout.src_b, "src_b")?,
+            reg(layout.dest, "dest")?,
+        );
+        let cond = self
+            .conditions()
+            .into_iter()
+            .find(|cond| self.cond_code(*cond) == Some(code));
+        let op = self
+            .operations()
+            .into_iter()
+            .find(|op| self.op_code(*op) == Some(code));
+
+        // conditions don't write anything, their destination is Z
+        match (cond, op) {
+            (Some(cond), _) if b == Reg::Zero && dest == Reg::Zero => {
+                Ok(Instruction::Condition { cond, reg: a })
+            }
+            (Some(Cond::Jump), _) if [a, b, dest] == [Reg::A; 3] => Ok(Instruction::Jump),
+            (_, Some(op)) => Ok(Instruction::Alu { op, a, b, dest }),
+            (Some(_), None) => Err(DecodeError::ConditionOperands),
+            (None, None) => Err(DecodeError::UnknownCode(code)),
+        }
+    }
+}
+
+/// Reason why a word can't be decoded
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DecodeError {
+    /// bits which should be 0 are set
+    ReservedBits(u16),
+    IllegalRegister { field: &'static str, code: u16 },
+    /// neither an operation nor a condition
+    UnknownCode(u16),
+    /// condition code used with operands of an operation
+    ConditionOperands,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecodeError::ReservedBits(bits) => write!(f, "reserved bits {bits:016b} are set"),
+            DecodeError::IllegalRegister { field, code } => {
+                write!(f, "illegal register code {code:03b} in {field}")
+            }
+            DecodeError::UnknownCode(code) => {
+                write!(f, "code {code:03b} is neither an operation nor a condition")
+            }
+            DecodeError::ConditionOperands => {
+                write!(f, "a condition must have Z as second operand and destination")
+            }
+        }
+    }
+}
+
+/// A group of bits in a word, given by its lowest bit and its width
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(from = "(u16, u16)")]
+pub struct Field {
+    pub offset: u16,
+    pub width: u16,
+}
+
+impl From<(u16, u16)> for Field {
+    fn from((offset, width): (u16, u16)) -> Self {
+        Field { offset, width }
+    }
+}
+
+impl Field {
+    pub fn mask(&self) -> u16 {
+        (((1_u32 << self.width) - 1) << self.offset) as u16
+    }
+
+    pub fn contains(&self, bit: u16) -> bool {
+        bit >= self.offset && bit < self.offset + self.width
+    }
+
+    /// returns the value held by this field in `word`
+    pub fn extract(&self, word: u16) -> u16 {
+        (word & self.mask()) >> self.offset
+    }
+
+    /// returns the word with `val` written in this field
+    pub fn insert(&self, word: u16, val: u16) -> u16 {
+        (word & !self.mask()) | ((val << self.offset) & self.mask())
+    }
+}
+
+/// Position of each field in a word,
+/// bits not covered by any field are left to 0
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Layout {
+    /// set to 1 for data mode words
+    pub mode: Field,
+    /// value loaded by a data mode word
+    pub value: Field,
+    /// operation or condition code
+    pub code: Field,
+    pub src_a: Field,
+    pub src_b: Field,
+    pub dest: Field,
+}
+
+/// Size of the memory cell an address points to
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AddressUnit {
+    #[default]
+    Word,
+    Byte,
+    Bit,
+}
+
+impl AddressUnit {
+    /// number of addresses taken by a 16 bits word
+    pub fn per_word(self) -> u16 {
+        match self {
+            AddressUnit::Word => 1,
+            AddressUnit::Byte => 2,
+            AddressUnit::Bit => 16,
+        }
+    }
+}
+
+/// Architectures known by the assembler and the one currently targeted
+pub struct Targets {
+    archs: Vec<Box<dyn Architecture>>,
+    active: usize,
+}
+
+impl Default for Targets {
+    fn default() -> Self {
+        Targets {
+            archs: vec![Box::new(arch_v1::ArchV1)],
+            active: 0,
+        }
+    }
+}
+
+impl Targets {
+    /// the architecture called `name`, if it exists
+    pub fn get(&self, name: &str) -> Option<&dyn Architecture> {
+        self.archs.iter().find(|arch| arch.name() == name).map(|arch| arch.as_ref())
+    }
+
+    /// target the architecture called `name`, returns false if it doesn't exist
+    pub fn select(&mut self, name: &str) -> bool {
+        match self.archs.iter().position(|arch| arch.name() == name) {
+            Some(index) => {
+                self.active = index;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn active(&self) -> &dyn Architecture {
+        self.archs[self.active].as_ref()
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        self.archs.iter().map(|arch| arch.name()).collect()
+    }
+
+    /// make a new architecture available, returns false if its name is already taken
+    pub fn add(&mut self, arch: Box<dyn Architecture>) -> bool {
+        if self.archs.iter().any(|known| known.name() == arch.name()) {
+            return false;
+        }
+        self.archs.push(arch);
+        true
+    }
+}
+
+/// Name of a register, operation or condition declared by an ISA file, it is
+/// stored inline so that they can be copied like the ones of `v1`
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Name {
+    len: u8,
+    bytes: [u8; Name::MAX_LEN],
+}
+
+impl Name {
+    pub const MAX_LEN: usize = 15;
+
+    /// None if `text` is longer than MAX_LEN bytes
+    pub fn new(text: &str) -> Option<Name> {
+        if text.len() > Name::MAX_LEN {
+            return None;
+        }
+        let mut bytes = [0; Name::MAX_LEN];
+        bytes[..text.len()].copy_from_slice(text.as_bytes());
+        Some(Name {
+            len: text.len() as u8,
+            bytes,
+        })
+    }
+
+    pub fn as_str(&self) -> &str {
+        // the bytes come from a str
+        std::str::from_utf8(&self.bytes[..self.len as usize]).unwrap_or_default()
+    }
+}
+
+impl fmt::Display for Name {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl fmt::Debug for Name {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self.as_str())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Op {
+    Add,
+    Sub,
+    And,
+    Not,
+    Or,
+    Xor,
+    /// declared by an ISA file, it takes two operands
+    Named(Name),
+}
+
+impl Op {
+    pub const ALL: [Op; 6] = [Op::Add, Op::Sub, Op::And, Op::Not, Op::Or, Op::Xor];
+}
+
+impl HandleToken for Op {
+    fn new(lex: &mut Lexer<crate::lexer::Token>) -> Result<Op, LexError> {
+        let op = match lex.slice().trim() {
+            "+" => Some(Op::Add),
+            "-" => Some(Op::Sub),
+            "&" => Some(Op::And),
+            "~" => Some(Op::Not),
+            "|" => Some(Op::Or),
+            "^" => Some(Op::Xor),
+            _ => None,
+        };
+        op.ok_or(LexError::UnexpectedCharacter)
+    }
+}
+
+impl Op {
+    /// the operation when the architecture has it
+    pub fn supported(self, arch: &dyn Architecture) -> Result<Op, LexError> {
+        match arch.op_code(self) {
+            Some(_) => Ok(self),
+            None => Err(LexError::Unsupported {
+                kind: "operation",
+                valid: arch.operations().iter().map(Op::to_string).collect(),
+            }),
+        }
+    }
+}
+
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let symbol = match self {
+            Op::Add => "+",
+            Op::Sub => "-",
+            Op::And => "&",
+            Op::Not => "~",
+            Op::Or => "|",
+            Op::Xor => "^",
+            Op::Named(name) => name.as_str(),
+        };
+        write!(f, "{symbol}")
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Reg {
+    A,
+    V,
+    AStar,
+    VStar,
+    D,
+    Zero,
+    One,
+    /// declared by an ISA file, `*name` reads and writes the memory
+    /// at the address held by `name`
+    Named(Name),
+}
+
+impl Reg {
+    pub const ALL: [Reg; 7] = [
+        Reg::A,
+        Reg::V,
+        Reg::AStar,
+        Reg::VStar,
+        Reg::D,
+        Reg::Zero,
+        Reg::One,
+    ];
+}
+
+impl HandleToken for Reg {
+    fn new(lex: &mut Lexer<crate::lexer::Token>) -> Result<Reg, LexError> {
+        let reg = match lex.slice() {
+            "A" => Some(Reg::A),
+            "V" => Some(Reg::V),
+            "*A" => Some(Reg::AStar),
+            "*V" => Some(Reg::VStar),
+            "D" => Some(Reg::D),
+            "Z" => Some(Reg::Zero),
+            "O" => Some(Reg::One),
+            // only known once the architecture is, `*B` can't be a name
+            other => Name::new(other).map(Reg::Named),
+        };
+        reg.ok_or_else(|| LexError::UnknownRegister {
+            valid: Reg::ALL.iter().map(Reg::to_string).collect(),
+        })
+    }
+}
+
+impl Reg {
+    /// the register when the architecture has it
+    pub fn supported(self, arch: &dyn Architecture) -> Result<Reg, LexError> {
+        match arch.reg_code(self) {
+            Some(_) => Ok(self),
+            None => Err(LexError::UnknownRegister {
+                valid: arch.registers().iter().map(Reg::to_string).collect(),
+            }),
+        }
+    }
+}
+
+impl fmt::Display for Reg {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Reg::A => "A",
+            Reg::V => "V",
+            Reg::AStar => "*A",
+            Reg::VStar => "*V",
+            Reg::D => "D",
+            Reg::Zero => "Z",
+            Reg::One => "O",
+            Reg::Named(name) => name.as_str(),
+        };
+        write!(f, "{name}")
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Cond {
+    Eq,
+    Neq,
+    Gt,
+    Lt,
+    GtEq,
+    LtEq,
+    Jump,
+    /// declared by an ISA file
+    Named(Name),
+}
+
+impl Cond {
+    pub const ALL: [Cond; 7] = [
+        Cond::Eq,
+        Cond::Neq,
+        Cond::Gt,
+        Cond::Lt,
+        Cond::GtEq,
+        Cond::LtEq,
+        Cond::Jump,
+    ];
+}
+
+impl HandleToken for Cond {
+    fn new(lex: &mut Lexer<crate::lexer::Token>) -> Result<Self, LexError>
+    where
+        Self: Sized,
+    {
+        let cond = match lex.slice() {
+            "==" => Some(Cond::Eq),
+            ">" => Some(Cond::Gt),
+            "<" => Some(Cond::Lt),
+            ">=" => Some(Cond::GtEq),
+            "<=" => Some(Cond::LtEq),
+            "!=" => Some(Cond::Neq),
+            "JMP" => Some(Cond::Jump),
+            _ => None,
+        };
+        cond.ok_or(LexError::UnexpectedCharacter)
+    }
+}
+
+impl Cond {
+    /// the condition when the architecture has it
+    pub fn supported(self, arch: &dyn Architecture) -> Result<Cond, LexError> {
+        match arch.cond_code(self) {
+            Some(_) => Ok(self),
+            None => Err(LexError::Unsupported {
+                kind: "condition",
+                valid: arch.conditions().iter().map(Cond::to_string).collect(),
+            }),
+        }
+    }
+}
+
+impl fmt::Display for Cond {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let symbol = match self {
+            Cond::Eq => "==",
+            Cond::Neq => "!=",
+            Cond::Gt => ">",
+            Cond::Lt => "<",
+            Cond::GtEq => ">=",
+            Cond::LtEq => "<=",
+            Cond::Jump => "JMP",
+            Cond::Named(name) => name.as_str(),
+        };
+        write!(f, "{symbol}")
+    }
+}
+
+/// An instruction of the cpu, independent of its encoding
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Instruction {
+    /// data mode word loading `value` into the load register
+    Load(u16),
+    /// `dest = a op b`, only `a` is used by `~`
+    Alu { op: Op, a: Reg, b: Reg, dest: Reg },
+    /// `reg cond`
+    Condition { cond: Cond, reg: Reg },
+    /// `JMP`
+    Jump,
+    /// word placed by `.word`, `.fill` or `.zero`, stored as is
+    Data(u16),
+}
+
+impl Instruction {
+    pub fn encode(&self, arch: &dyn Architecture) -> u16 {
+        let layout = arch.layout();
+        // the parser only emits registers, operations and conditions known by the architecture
+        let reg = |reg: Reg| arch.reg_code(reg).unwrap_or_default();
+        let inst = |code: Option<u16>, a: Reg, b: Reg, dest: Reg| {
+            layout.code.insert(0, code.unwrap_or_default())
+                | layout.src_a.insert(0, reg(a))
+                | layout.src_b.insert(0, reg(b))
+                | layout.dest.insert(0, reg(dest))
+        };
 
-    #[allow(dead_code)]
+        match *self {
+            Instruction::Load(value) => layout.mode.insert(0, 1) | layout.value.insert(0, value),
+            Instruction::Alu { op, a, b, dest } => inst(arch.op_code(op), a, b, dest),
+            Instruction::Condition { cond, reg } => {
+                inst(arch.cond_code(cond), reg, Reg::Zero, Reg::Zero)
+            }
+            Instruction::Jump => inst(arch.cond_code(Cond::Jump), Reg::A, Reg::A, Reg::A),
+            Instruction::Data(word) => word,
+        }
+    }
+}
+
+pub mod arch_v1 {
+    use super::*;
 
     pub const MAX_LOAD_VALUE: u16 = 2_u16.pow(15) - 1;
 
-    #[derive(Clone, Copy, Debug, PartialEq)]
-    pub enum Op {
-        Add,
-        Sub,
-        And,
-        Not,
-        Or,
-        Xor,
-    }
-
-    impl HandleToken for Op {
-        fn new(lex: &mut Lexer<crate::lexer::Token>) -> Option<Op> {
-            match lex.slice().trim() {
-                "+" => Some(Op::Add),
-                "-" => Some(Op::Sub),
-                "&" => Some(Op::And),
-                "~" => Some(Op::Not),
-                "|" => Some(Op::Or),
-                "^" => Some(Op::Xor),
-                _ => None, // todo: return a beautiful error
-            }
-        }
-
-        fn bit_stream(&self) -> String {
-            match self {
-                Op::Add => "000",
-                Op::Sub => "001",
-                Op::And => "010",
-                Op::Or => "011",
-                Op::Xor => "100",
-                Op::Not => "101"
-            }.to_string()
-        }
-    }
-
-    #[derive(Clone, Copy, Debug, PartialEq)]
-    pub enum Reg {
-        A,
-        V,
-        AStar,
-        VStar,
-        D,
-        Zero,
-        One
+    /// First revision of the cpu
+    pub struct ArchV1;
+
+    impl Architecture for ArchV1 {
+        fn name(&self) -> &str {
+            "v1"
+        }
+
+        fn max_load_value(&self) -> u16 {
+            MAX_LOAD_VALUE
+        }
+
+        fn load_register(&self) -> Reg {
+            Reg::A
+        }
+
+        fn layout(&self) -> Layout {
+            Layout {
+                mode: Field::from((15, 1)),
+                value: Field::from((0, 15)),
+                code: Field::from((12, 3)),
+                src_a: Field::from((6, 3)),
+                src_b: Field::from((3, 3)),
+                dest: Field::from((0, 3)),
+            }
+        }
+
+        fn address_unit(&self) -> AddressUnit {
+            AddressUnit::Word
+        }
+
+        fn reg_code(&self, reg: Reg) -> Option<u16> {
+            Some(match reg {
+                Reg::A => 0b000,
+                Reg::AStar => 0b001,
+                Reg::V => 0b010,
+                Reg::VStar => 0b011,
+                Reg::D => 0b100,
+                Reg::Zero => 0b110,
+                Reg::One => 0b111,
+                Reg::Named(_) => return None,
+            })
+        }
+
+        fn op_code(&self, op: Op) -> Option<u16> {
+            Some(match op {
+                Op::Add => 0b000,
+                Op::Sub => 0b001,
+                Op::And => 0b010,
+                Op::Or => 0b011,
+                Op::Xor => 0b100,
+                Op::Not => 0b101,
+                Op::Named(_) => return None,
+            })
+        }
+
+        fn cond_code(&self, cond: Cond) -> Option<u16> {
+            Some(match cond {
+                Cond::Eq => 0b010,
+                Cond::Neq => 0b101,
+                Cond::Gt => 0b001,
+                Cond::Lt => 0b100,
+                Cond::GtEq => 0b011,
+                Cond::LtEq => 0b110,
+                Cond::Jump => 0b111,
+                Cond::Named(_) => return None,
+            })
+        }
+
+        fn conflicts(&self, dest: Reg, src: Reg) -> bool {
+            matches!((dest, src), (Reg::A, Reg::AStar) | (Reg::V, Reg::VStar))
+        }
+    }
+}
+
+/// Architecture described by an ISA file loaded at runtime,
+/// see `examples/arch_v1.toml` for the format
+pub mod isa {
+    use super::*;
+    use crate::lexer::Token;
+    use logos::Logos;
+    use miette::{miette, Error, LabeledSpan, NamedSource};
+    use std::collections::HashMap;
+    use toml::Spanned;
+
+    #[derive(Deserialize)]
+    #[serde(deny_unknown_fields)]
+    struct Description {
+        name: String,
+        max_load_value: Spanned<u16>,
+        load_register: Option<Spanned<String>>,
+        layout: Spanned<Layout>,
+        #[serde(default)]
+        address_unit: AddressUnit,
+        registers: HashMap<Spanned<String>, Spanned<u16>>,
+        operations: HashMap<Spanned<String>, Spanned<u16>>,
+        conditions: HashMap<Spanned<String>, Spanned<u16>>,
+        #[serde(default)]
+        constraints: Vec<Constraint>,
+    }
+
+    /// `write` can't be the destination of an instruction reading `read`
+    #[derive(Deserialize)]
+    #[serde(deny_unknown_fields)]
+    struct Constraint {
+        write: Spanned<String>,
+        read: Spanned<String>,
+    }
+
+    pub struct IsaFile {
+        name: String,
+        max_load_value: u16,
+        load_register: Reg,
+        layout: Layout,
+        address_unit: AddressUnit,
+        registers: Vec<(Reg, u16)>,
+        operations: Vec<(Op, u16)>,
+        conditions: Vec<(Cond, u16)>,
+        constraints: Vec<(Reg, Reg)>,
+    }
+
+    impl Architecture for IsaFile {
+        fn name(&self) -> &str {
+            &self.name
+        }
+
+        fn max_load_value(&self) -> u16 {
+            self.max_load_value
+        }
+
+        fn load_register(&self) -> Reg {
+            self.load_register
+        }
+
+        fn layout(&self) -> Layout {
+            self.layout
+        }
+
+        fn address_unit(&self) -> AddressUnit {
+            self.address_unit
+        }
+
+        fn reg_code(&self, reg: Reg) -> Option<u16> {
+            code_of(&self.registers, reg)
+        }
+
+        fn op_code(&self, op: Op) -> Option<u16> {
+            code_of(&self.operations, op)
+        }
+
+        fn cond_code(&self, cond: Cond) -> Option<u16> {
+            code_of(&self.conditions, cond)
+        }
+
+        fn conflicts(&self, dest: Reg, src: Reg) -> bool {
+            self.constraints.contains(&(dest, src))
+        }
+
+        fn registers(&self) -> Vec<Reg> {
+            self.registers.iter().map(|(reg, _)| *reg).collect()
+        }
+
+        fn operations(&self) -> Vec<Op> {
+            self.operations.iter().map(|(op, _)| *op).collect()
+        }
+
+        fn conditions(&self) -> Vec<Cond> {
+            self.conditions.iter().map(|(cond, _)| *cond).collect()
+        }
     }
 
-    impl HandleToken for Reg {
-        fn new(lex: &mut Lexer<crate::lexer::Token>) -> Option<Reg> {
-            match lex.slice() {
-                "A" => Some(Reg::A),
-                "V" => Some(Reg::V),
-                "*A" => Some(Reg::AStar),
-                "*V" => Some(Reg::VStar),
-                "D" => Some(Reg::D),
-                "Z" => Some(Reg::Zero),
-                "O" => Some(Reg::One),
-                _ => None, // todo: return a beautiful error
-            }
-        }
-
-        fn bit_stream(&self) -> String {
-            match self {
-                Reg::A => "000",
-                Reg::AStar => "001",
-                Reg::V => "010",
-                Reg::VStar => "011",
-                Reg::D => "100",
-                Reg::Zero => "110",
-                Reg::One => "111",
-            }
-            .to_string()
-        } // todo: colorize the string depending on the register
-    }
-
-    #[derive(Clone, Copy, Debug, PartialEq)]
-    pub enum Cond {
-        Eq,
-        Neq,
-        Gt,
-        Lt,
-        GtEq,
-        LtEq,
-        Jump,
-    }
-
-    impl HandleToken for Cond {
-        fn new(lex: &mut Lexer<crate::lexer::Token>) -> Option<Self>
-        where
-            Self: Sized,
-        {
-            match lex.slice() {
-                "==" => Some(Cond::Eq),
-                ">" => Some(Cond::Gt),
-                "<" => Some(Cond::Lt),
-                ">=" => Some(Cond::GtEq),
-                "<=" => Some(Cond::LtEq),
-                "!=" => Some(Cond::Neq),
-                "JMP" => Some(Cond::Jump),
-                _ => None, // todo error
-            }
-        }
-
-        fn bit_stream(&self) -> String {
-            match self {
-                Cond::Eq => "010",
-                Cond::Neq => "101",
-                Cond::Gt => "001",
-                Cond::Lt => "100",
-                Cond::GtEq => "011",
-                Cond::LtEq => "110",
-                Cond::Jump => "111",
-            }
-            .to_string()
-        }
-    }
-
-
-    // wrapper around Op and Cond
-    #[allow(dead_code)]
-    pub enum OpOrCond {
-        Operation(Op),
-        Condition(Cond),
-    }
-
-    impl HandleToken for OpOrCond {
-        fn new(_lex: &mut Lexer<crate::lexer::Token>) -> Option<Self>
-            where
-                Self: Sized {
-            None
-        } 
-
-        fn bit_stream(&self) -> String {
-            match self {
-                OpOrCond::Operation(op) => op.bit_stream(),
-                OpOrCond::Condition(cond) => cond.bit_stream(),
-            }
-        }
-    }
-
-    /// takes a 15 bits value and format it in a recognizable word for the cpu
-    #[allow(dead_code)]
-    pub fn data_mode_format(val: u16) -> String {
-        format!("{}{}", "1".green(), format!("{:015b}", val).red())
-    }
-
-    /// takes operands operation and destination register and format it in a recognizable word for the cpu
-    #[allow(dead_code)]
-    pub fn inst_mode_format(op_or_cond: OpOrCond, rega: Reg, regb: Reg, regc: Reg) -> String {
-        format!(
-            "{}{}000{}{}{}",
-            "0".green().bold(),
-            op_or_cond.bit_stream().blue(),
-            rega.bit_stream().yellow(),
-            regb.bit_stream().purple(),
-            regc.bit_stream().cyan()
-        )
+    fn code_of<T: PartialEq>(codes: &[(T, u16)], item: T) -> Option<u16> {
+        codes.iter().find(|(known, _)| *known == item).map(|(_, code)| *code)
+    }
+
+    /// read and check the ISA file located at `path`
+    pub fn load(path: &str) -> Result<IsaFile, Vec<Error>> {
+        match std::fs::read_to_string(path) {
+            Ok(content) => parse(path, &content),
+            Err(e) => Err(vec![miette!("Error: Unable to read ISA file {path}: {e}")]),
+        }
+    }
+
+    /// check an ISA description, `path` is only used in the reports
+    pub fn parse(path: &str, content: &str) -> Result<IsaFile, Vec<Error>> {
+        let with_source = |errors: Vec<Error>| -> Vec<Error> {
+            errors
+                .into_iter()
+                .map(|e| e.with_source_code(NamedSource::new(path, content.to_string())))
+                .collect()
+        };
+
+        let description: Description = match toml::from_str(content) {
+            Ok(description) => description,
+            Err(e) => {
+                let report = miette!(
+                    labels = vec![LabeledSpan::at(e.span().unwrap_or(0..0), "Here")],
+                    "Error: {}",
+                    e.message()
+                );
+                return Err(with_source(vec![report]));
+            }
+        };
+
+        let mut errors: Vec<Error> = vec![];
+
+        let layout = check_layout(&description, &mut errors);
+        let registers = check_codes(&Reg::ALL, "register", &description.registers, layout.src_a, Reg::Named, &mut errors);
+        let operations = check_codes(&Op::ALL, "operation", &description.operations, layout.code, Op::Named, &mut errors);
+        let conditions = check_codes(&Cond::ALL, "condition", &description.conditions, layout.code, Cond::Named, &mut errors);
+
+        // the parser reads a new name as the first kind declaring it
+        let mut names: Vec<(&Spanned<String>, &str)> = vec![];
+        let declared = [
+            ("register", &description.registers),
+            ("operation", &description.operations),
+            ("condition", &description.conditions),
+        ];
+        for (kind, table) in declared {
+            for name in table.keys() {
+                if let Some((other, other_kind)) = names.iter().find(|(other, _)| other.get_ref() == name.get_ref()) {
+                    errors.push(miette!(
+                        labels = vec![
+                            LabeledSpan::at(other.span(), format!("the {other_kind}")),
+                            LabeledSpan::at(name.span(), format!("and the {kind}")),
+                        ],
+                        "Error: {} names two different things",
+                        name.get_ref()
+                    ));
+                }
+                names.push((name, kind));
+            }
+        }
+
+        // those are used by the assembler to encode register transfers and jumps
+        let required = [
+            (code_of(&registers, Reg::Zero).is_none(), "register Z"),
+            (code_of(&operations, Op::Or).is_none(), "operation |"),
+            (code_of(&conditions, Cond::Jump).is_none(), "condition JMP"),
+        ];
+        for (missing, item) in required {
+            if missing {
+                errors.push(miette!(
+                    help = "It is needed to encode register transfers and jumps",
+                    "Error: The {item} must be declared"
+                ));
+            }
+        }
+
+        // the constraints and the load register are declared registers
+        let declared: Vec<Reg> = registers.iter().map(|(reg, _)| *reg).collect();
+        let mut constraints = vec![];
+        for constraint in &description.constraints {
+            let write = find(&declared, "register", &constraint.write, &mut errors);
+            let read = find(&declared, "register", &constraint.read, &mut errors);
+            if let (Some(write), Some(read)) = (write, read) {
+                constraints.push((write, read));
+            }
+        }
+
+        let load_register = match &description.load_register {
+            Some(name) => find(&declared, "register", name, &mut errors).unwrap_or(Reg::A),
+            None => Reg::A,
+        };
+
+        if !errors.is_empty() {
+            return Err(with_source(errors));
+        }
+
+        Ok(IsaFile {
+            name: description.name,
+            max_load_value: *description.max_load_value.get_ref(),
+            load_register,
+            layout,
+            address_unit: description.address_unit,
+            registers,
+            operations,
+            conditions,
+            constraints,
+        })
+    }
+
+    fn check_layout(description: &Description, errors: &mut Vec<Error>) -> Layout {
+        let layout = *description.layout.get_ref();
+        let span = description.layout.span();
+
+        let fields = [
+            ("mode", layout.mode),
+            ("value", layout.value),
+            ("code", layout.code),
+            ("src_a", layout.src_a),
+            ("src_b", layout.src_b),
+            ("dest", layout.dest),
+        ];
+        for (name, field) in fields {
+            if field.width == 0 || field.offset + field.width > 16 {
+                errors.push(miette!(
+                    labels = vec![LabeledSpan::at(span.clone(), "in this layout")],
+                    "Error: The {name} field doesn't fit in a 16 bits word"
+                ));
+                // the masks below can't be computed
+                return layout;
+            }
+        }
+
+        if layout.mode.width != 1 {
+            errors.push(miette!(
+                labels = vec![LabeledSpan::at(span.clone(), "in this layout")],
+                "Error: The mode field must be a single bit"
+            ));
+        }
+
+        // a data mode word is made of the mode and value fields,
+        // an instruction of all the others
+        let mut overlaps = vec![];
+        if layout.mode.mask() & layout.value.mask() != 0 {
+            overlaps.push(("mode", "value"));
+        }
+        let inst_fields = [fields[0], fields[2], fields[3], fields[4], fields[5]];
+        for (i, (name, field)) in inst_fields.iter().enumerate() {
+            for (other_name, other) in &inst_fields[i + 1..] {
+                if field.mask() & other.mask() != 0 {
+                    overlaps.push((name, other_name));
+                }
+            }
+        }
+        for (name, other_name) in overlaps {
+            errors.push(miette!(
+                labels = vec![LabeledSpan::at(span.clone(), "in this layout")],
+                "Error: The {name} and {other_name} fields overlap"
+            ));
+        }
+
+        let max_load_value = &description.max_load_value;
+        if *max_load_value.get_ref() > layout.value.mask() >> layout.value.offset {
+            errors.push(miette!(
+                labels = vec![LabeledSpan::at(max_load_value.span(), "too big")],
+                "Error: The max load value doesn't fit in the {} bits of the value field",
+                layout.value.width
+            ));
+        }
+
+        // registers codes are checked against src_a, the other ones must be as wide
+        if layout.src_b.width != layout.src_a.width || layout.dest.width != layout.src_a.width {
+            errors.push(miette!(
+                labels = vec![LabeledSpan::at(span.clone(), "in this layout")],
+                "Error: The src_a, src_b and dest fields must have the same width"
+            ));
+        }
+
+        layout
+    }
+
+    fn find<T: Copy + fmt::Display>(
+        all: &[T],
+        kind: &str,
+        name: &Spanned<String>,
+        errors: &mut Vec<Error>,
+    ) -> Option<T> {
+        let found = all.iter().find(|item| item.to_string() == *name.get_ref());
+        if found.is_none() {
+            let valid: Vec<String> = all.iter().map(|item| item.to_string()).collect();
+            errors.push(miette!(
+                labels = vec![LabeledSpan::at(name.span(), format!("unknown {kind}"))],
+                help = format!("Expected one of {}", valid.join(" ")),
+                "Error: Unknown {kind} {}",
+                name.get_ref()
+            ));
+        }
+        found.copied()
+    }
+
+    /// the item of `v1` called `name` or a new one, the lexer must read its name
+    /// as a single identifier, or as a register for `*name`
+    fn declare<T: Copy + fmt::Display>(
+        all: &[T],
+        kind: &str,
+        name: &Spanned<String>,
+        named: fn(Name) -> T,
+        errors: &mut Vec<Error>,
+    ) -> Option<T> {
+        let text = name.get_ref();
+        if let Some(item) = all.iter().find(|item| item.to_string() == *text) {
+            return Some(*item);
+        }
+
+        let mut lex = Token::lexer(text);
+        let word = match (lex.next(), lex.next()) {
+            (Some(Ok(Token::Identifier(id))), None) => !id.contains('.'),
+            (Some(Ok(Token::Register(Reg::Named(_)))), None) => kind == "register",
+            _ => false,
+        };
+        match Name::new(text) {
+            Some(new) if word => Some(named(new)),
+            _ => {
+                errors.push(miette!(
+                    labels = vec![LabeledSpan::at(name.span(), "not a name")],
+                    help = format!(
+                        "A new name is a word of at most {} letters, digits and `_`, a register can also be `*` and a capitalized word",
+                        Name::MAX_LEN
+                    ),
+                    "Error: {text} isn't a valid {kind} name"
+                ));
+                None
+            }
+        }
+    }
+
+    fn check_codes<T: Copy + PartialEq + fmt::Display>(
+        all: &[T],
+        kind: &str,
+        declared: &HashMap<Spanned<String>, Spanned<u16>>,
+        field: Field,
+        named: fn(Name) -> T,
+        errors: &mut Vec<Error>,
+    ) -> Vec<(T, u16)> {
+        let mut entries: Vec<_> = declared.iter().collect();
+        // keep the reports in the order of the file
+        entries.sort_by_key(|(name, _)| name.span().start);
+
+        let mut codes: Vec<(T, u16, &Spanned<u16>)> = vec![];
+        for (name, code) in entries {
+            let Some(item) = declare(all, kind, name, named, errors) else {
+                continue;
+            };
+            if field.width < 16 && *code.get_ref() >> field.width != 0 {
+                errors.push(miette!(
+                    labels = vec![LabeledSpan::at(code.span(), "too big")],
+                    "Error: The code of {kind} {item} doesn't fit in {} bits",
+                    field.width
+                ));
+            }
+            if let Some((other, _, other_code)) = codes.iter().find(|(_, c, _)| c == code.get_ref()) {
+                errors.push(miette!(
+                    labels = vec![
+                        LabeledSpan::at(other_code.span(), format!("used by {other}")),
+                        LabeledSpan::at(code.span(), format!("and by {item}")),
+                    ],
+                    "Error: Two {kind}s share the same code"
+                ));
+            }
+            codes.push((item, *code.get_ref(), code));
+        }
+
+        codes.into_iter().map(|(item, code, _)| (item, code)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::arch_v1::ArchV1;
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    const ISA_V1: &str = include_str!("../examples/arch_v1.toml");
+
+    #[test]
+    fn test_decode_v1() {
+        assert_eq!(Ok(Instruction::Load(0x7fff)), ArchV1.decode(0xffff));
+        assert_eq!(
+            Ok(Instruction::Alu {
+                op: Op::And,
+                a: Reg::D,
+                b: Reg::AStar,
+                dest: Reg::D
+            }),
+            ArchV1.decode(0b0010000100001100)
+        );
+        assert_eq!(
+            Ok(Instruction::Condition {
+                cond: Cond::Neq,
+                reg: Reg::D
+            }),
+            ArchV1.decode(0b0101000100110110)
+        );
+        assert_eq!(Ok(Instruction::Jump), ArchV1.decode(0b0111000000000000));
+
+        // padding bits between the code and the registers
+        assert_eq!(
+            Err(DecodeError::ReservedBits(0b0000001000000000)),
+            ArchV1.decode(0b0010001100001100)
+        );
+        // 101 isn't a register
+        assert_eq!(
+            Err(DecodeError::IllegalRegister {
+                field: "src_b",
+                code: 0b101
+            }),
+            ArchV1.decode(0b0010000100101100)
+        );
+        // 110 is only a condition code
+        assert_eq!(
+            Err(DecodeError::ConditionOperands),
+            ArchV1.decode(0b0110000100001100)
+        );
+        assert_eq!(
+            Err(DecodeError::ConditionOperands),
+            ArchV1.decode(0b0111000100000100)
+        );
+    }
+
+    #[test]
+    fn test_isa_file_matches_v1() {
+        let isa = isa::parse("arch_v1.toml", ISA_V1).unwrap();
+
+        assert_eq!("v1_file", isa.name());
+        assert_eq!(ArchV1.max_load_value(), isa.max_load_value());
+        assert_eq!(ArchV1.load_register(), isa.load_register());
+        assert_eq!(ArchV1.layout(), isa.layout());
+        assert_eq!(ArchV1.address_unit(), isa.address_unit());
+        for reg in Reg::ALL {
+            assert_eq!(ArchV1.reg_code(reg), isa.reg_code(reg));
+            for src in Reg::ALL {
+                assert_eq!(ArchV1.conflicts(reg, src), isa.conflicts(reg, src));
+            }
+        }
+        for op in Op::ALL {
+            assert_eq!(ArchV1.op_code(op), isa.op_code(op));
+        }
+        for cond in Cond::ALL {
+            assert_eq!(ArchV1.cond_code(cond), isa.cond_code(cond));
+        }
+    }
+
+    #[test]
+    fn test_wrong_isa_file() {
+        // register name, code too big and missing Z register
+        let src = ISA_V1
+            .replace("Z = 0b110", "\"B.x\" = 0b110")
+            .replace("D = 0b100", "D = 0b1000");
+        assert_eq!(3, isa::parse("wrong.toml", &src).err().unwrap().len());
+
+        // overlapping fields
+        let src = ISA_V1.replace("dest = [0, 3]", "dest = [1, 3]");
+        assert_eq!(1, isa::parse("wrong.toml", &src).err().unwrap().len());
+
+        assert!(isa::parse("wrong.toml", "name = 3").is_err());
+
+        let src = ISA_V1.replace("address_unit = \"word\"", "address_unit = \"nibble\"");
+        assert_eq!(1, isa::parse("wrong.toml", &src).err().unwrap().len());
+    }
+
+    #[test]
+    fn test_isa_file_drives_names() {
+        let src = ISA_V1
+            .replace("name = \"v1_file\"", "name = \"no_v\"")
+            .replace("V = 0b010", "")
+            .replace("\"^\" = 0b100", "")
+            .replace("write = \"V\"", "write = \"D\"");
+
+        let mut targets = Targets::default();
+        assert!(targets.add(Box::new(isa::parse("no_v.toml", &src).unwrap())));
+        assert!(!targets.add(Box::new(isa::parse("no_v.toml", &src).unwrap())));
+        assert!(targets.select("no_v"));
+
+        let arch = targets.active();
+        assert_eq!(Reg::D.supported(arch), Ok(Reg::D));
+        assert!(matches!(
+            Reg::V.supported(arch),
+            Err(LexError::UnknownRegister { valid }) if !valid.contains(&"V".to_string())
+        ));
+        assert!(matches!(
+            Op::Xor.supported(arch),
+            Err(LexError::Unsupported { kind: "operation", valid }) if valid.len() == 5
+        ));
+        assert_eq!(Op::And.supported(arch), Ok(Op::And));
+    }
+
+    #[test]
+    fn test_isa_file_new_names() {
+        let src = ISA_V1
+            .replace("V = 0b010", "B = 0b010")
+            .replace("\"*V\" = 0b011", "\"*B\" = 0b011")
+            .replace("\"^\" = 0b100", "XOR = 0b100\nSHL = 0b110")
+            .replace("\"!=\" = 0b101", "NZ = 0b101")
+            .replace("write = \"V\"", "write = \"B\"")
+            .replace("read = \"*V\"", "read = \"*B\"");
+        let isa = isa::parse("new_names.toml", &src).unwrap();
+
+        let named = |name| Name::new(name).unwrap();
+        let (b, b_star) = (Reg::Named(named("B")), Reg::Named(named("*B")));
+        assert_eq!(Some(0b010), isa.reg_code(b));
+        assert_eq!(None, isa.reg_code(Reg::V));
+        assert!(isa.conflicts(b, b_star));
+        let shl = Instruction::Alu {
+            op: Op::Named(named("SHL")),
+            a: b,
+            b: b_star,
+            dest: Reg::D,
+        };
+        assert_eq!(0b0110000010011100, shl.encode(&isa));
+        assert_eq!(Ok(shl), isa.decode(0b0110000010011100));
+
+        // not a single word, a directive, a name taken twice
+        let src = ISA_V1
+            .replace("D = 0b100", "D = 0b100\n\"*b\" = 0b101\nB = 0b101")
+            .replace("\"^\" = 0b100", "ORG = 0b100")
+            .replace("\"!=\" = 0b101", "B = 0b101");
+        assert_eq!(3, isa::parse("wrong.toml", &src).err().unwrap().len());
     }
 }
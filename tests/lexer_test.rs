@@ -2,6 +2,7 @@ use std::fs::{read_to_string, File};
 use pretty_assertions::assert_eq;
 
 #[path = "../src/lexer.rs"]
+#[allow(dead_code)]
 mod lexer;
 
 #[test]
@@ -18,6 +19,7 @@ fn test_lexer() {
     let mut temp_file = OpenOptions::new()
         .create(true)
         .write(true)
+        .truncate(true)
         .open(temp_file_path)
         .unwrap();
 
@@ -25,9 +27,9 @@ fn test_lexer() {
         let mut content = String::new();
         let _ = source_file.read_to_string(&mut content);
 
-        let mut lex = Token::lexer(content.as_str());
+        let lex = Token::lexer(content.as_str());
 
-        while let Some(result) = lex.next() {
+        for result in lex {
             writeln!(temp_file, "{:?}", result).unwrap();
         }
     }
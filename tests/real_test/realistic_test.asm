@@ -1,6 +1,6 @@
 DEFINE io_adr 0x7fff
-DEFINE ob_detection_mask 0x100
-DEFINE movement_mask 0x600
+DEFINE ob_detection_mask 1 << 8
+DEFINE movement_mask 0b11 << 9
 DEFINE move_mask 0x4
 DEFINE left 0x8
 